@@ -25,6 +25,27 @@ impl BoundingBox {
         [self.xmin, self.ymin, self.xmax, self.ymax]
     }
 
+    /// Whether self and other overlap (touching boxes don't)
+    pub fn intersects(&self, other: &BoundingBox) -> bool {
+        self.xmin < other.xmax
+            && other.xmin < self.xmax
+            && self.ymin < other.ymax
+            && other.ymin < self.ymax
+    }
+
+    /// The overlap of self and other, if they intersect
+    pub fn intersection(&self, other: &BoundingBox) -> Option<BoundingBox> {
+        if !self.intersects(other) {
+            return None;
+        }
+        Some(BoundingBox {
+            xmin: self.xmin.max(other.xmin),
+            ymin: self.ymin.max(other.ymin),
+            xmax: self.xmax.min(other.xmax),
+            ymax: self.ymax.min(other.ymax),
+        })
+    }
+
     /// Extend self to contain other
     pub fn extend(&mut self, other: &BoundingBox) {
         self.xmin = self.xmin.min(other.xmin);
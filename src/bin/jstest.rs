@@ -6,10 +6,10 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::io::BufWriter;
 use std::time::Instant;
-use tilemachine::custom_script::CustomScript;
+use tilemachine::custom_script::{CustomScript, OutputImage, ParamValues};
 use tilemachine::source::open_source;
 use tilemachine::utils::ImageData;
-use tilemachine::xyz::TileCoords;
+use tilemachine::xyz::{TileCoords, TILE_SIZE};
 
 fn save_tile(image_data: &ImageData<u8>) {
     let mut out_buf = Vec::new();
@@ -44,8 +44,18 @@ fn main() {
         zoom: 20,
     };
     let start = Instant::now();
-    let out_data = script.execute_on_tile(&tile_coords, &open_source).unwrap();
+    let out_data = script
+        .execute_on_tile(
+            &tile_coords,
+            TILE_SIZE as usize,
+            &ParamValues::new(),
+            &open_source,
+        )
+        .unwrap();
     let duration = start.elapsed();
     println!("took {:?}", duration);
-    save_tile(&out_data);
+    match out_data {
+        OutputImage::Rgba(image_data) => save_tile(&image_data),
+        OutputImage::Float32(_) => println!("not saving float output"),
+    }
 }
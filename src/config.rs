@@ -0,0 +1,276 @@
+//! Configuration of the server, read from a TOML file (see `config/dev.toml`). Command line
+//! flags take precedence over the file
+use crate::utils::{Error, Result};
+use clap::Parser;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug, Default)]
+#[command(about = "XYZ tiles and WMS server computing rasters from custom scripts")]
+pub struct Cli {
+    /// TOML configuration file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long)]
+    pub bind: Option<String>,
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Number of HTTP workers, defaults to the number of CPUs
+    #[arg(long)]
+    pub workers: Option<usize>,
+    /// Directory of the web UI
+    #[arg(long)]
+    pub static_dir: Option<PathBuf>,
+    /// One of error, warn, info, debug, trace
+    #[arg(long)]
+    pub log_level: Option<String>,
+    /// S3 endpoint (host:port) to use instead of AWS, for example for MinIO
+    #[arg(long)]
+    pub s3_endpoint: Option<String>,
+    #[arg(long)]
+    pub s3_access_key_id: Option<String>,
+    #[arg(long)]
+    pub s3_secret_access_key: Option<String>,
+    #[arg(long)]
+    pub s3_region: Option<String>,
+    /// GDAL configuration option, can be repeated
+    #[arg(long = "gdal-option", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+    pub gdal_options: Vec<(String, String)>,
+}
+
+fn parse_key_value(s: &str) -> std::result::Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got {}", s))
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub s3: S3Config,
+    /// Additional GDAL configuration options, e.g. GDAL_CACHEMAX
+    pub gdal: BTreeMap<String, String>,
+    pub render: RenderConfig,
+    pub tile_cache: TileCacheConfig,
+    pub dataset_pool: DatasetPoolConfig,
+    pub script_store: ScriptStoreConfig,
+    /// JSON definitions (OGC TMS 2.0) of tile matrix sets to serve tiles in, in addition to the
+    /// built-in WebMercatorQuad and WorldCRS84Quad
+    pub tile_matrix_sets: Vec<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+    // Defaults to the number of CPUs
+    pub workers: Option<usize>,
+    pub static_dir: PathBuf,
+    pub log_level: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: "0.0.0.0".to_string(),
+            port: 8080,
+            workers: None,
+            static_dir: PathBuf::from("./web"),
+            log_level: "info".to_string(),
+        }
+    }
+}
+
+/// Access to rasters on S3 (`s3:` inputs). Credentials that are not set here are looked up by
+/// GDAL in the usual places (AWS_* environment variables, ~/.aws, instance metadata)
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    pub endpoint: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub region: Option<String>,
+    pub https: bool,
+    pub virtual_hosting: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            endpoint: None,
+            access_key_id: None,
+            secret_access_key: None,
+            region: None,
+            https: true,
+            virtual_hosting: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderConfig {
+    // Defaults to 4 times the number of CPUs
+    pub threads: Option<usize>,
+    // Defaults to 16 times the number of threads
+    pub queue_size: Option<usize>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+pub enum TileCacheConfig {
+    Memory {
+        // Maximum number of tiles
+        #[serde(default = "default_tile_cache_size")]
+        size: NonZeroUsize,
+    },
+    Disk {
+        dir: PathBuf,
+    },
+    None,
+}
+
+fn default_tile_cache_size() -> NonZeroUsize {
+    NonZeroUsize::new(1024).unwrap()
+}
+
+impl Default for TileCacheConfig {
+    fn default() -> Self {
+        TileCacheConfig::Memory {
+            size: default_tile_cache_size(),
+        }
+    }
+}
+
+/// Where scripts registered with `POST /scripts` are kept
+#[derive(Deserialize, Debug, Default)]
+#[serde(tag = "backend", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ScriptStoreConfig {
+    // Registered scripts are lost on restart
+    #[default]
+    Memory,
+    Disk {
+        dir: PathBuf,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatasetPoolConfig {
+    /// Maximum number of open datasets, idle or in use
+    pub max_open: usize,
+    /// Maximum number of idle datasets kept open for later requests
+    pub max_idle: usize,
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for DatasetPoolConfig {
+    fn default() -> Self {
+        DatasetPoolConfig {
+            max_open: 256,
+            max_idle: 256,
+            idle_timeout_secs: 300,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_toml(toml_str: &str) -> Result<Config> {
+        toml::from_str(toml_str).map_err(|e| Error::ConfigError(e.to_string()))
+    }
+
+    /// Read the configuration file given on the command line (if any) and apply the flags
+    pub fn load(cli: Cli) -> Result<Config> {
+        let mut config = match &cli.config {
+            Some(path) => {
+                let toml_str = std::fs::read_to_string(path)
+                    .map_err(|e| Error::ConfigError(format!("{}: {}", path.display(), e)))?;
+                Config::from_toml(&toml_str)?
+            }
+            None => Config::default(),
+        };
+        let server = &mut config.server;
+        server.bind = cli.bind.unwrap_or(std::mem::take(&mut server.bind));
+        server.port = cli.port.unwrap_or(server.port);
+        server.workers = cli.workers.or(server.workers);
+        server.static_dir = cli
+            .static_dir
+            .unwrap_or(std::mem::take(&mut server.static_dir));
+        server.log_level = cli
+            .log_level
+            .unwrap_or(std::mem::take(&mut server.log_level));
+        let s3 = &mut config.s3;
+        s3.endpoint = cli.s3_endpoint.or(s3.endpoint.take());
+        s3.access_key_id = cli.s3_access_key_id.or(s3.access_key_id.take());
+        s3.secret_access_key = cli.s3_secret_access_key.or(s3.secret_access_key.take());
+        s3.region = cli.s3_region.or(s3.region.take());
+        config.gdal.extend(cli.gdal_options);
+        Ok(config)
+    }
+
+    /// The GDAL configuration options to set: sensible defaults for cloud-optimized rasters, the
+    /// S3 settings and then the explicit options from the `gdal` section
+    pub fn gdal_options(&self) -> BTreeMap<String, String> {
+        let mut options = BTreeMap::new();
+        let mut set = |key: &str, value: &str| options.insert(key.to_string(), value.to_string());
+        set("VSI_CACHE", "TRUE");
+        set("GDAL_DISABLE_READDIR_ON_OPEN", "TRUE");
+        let bool_str = |b: bool| if b { "TRUE" } else { "FALSE" };
+        set("AWS_HTTPS", bool_str(self.s3.https));
+        set("AWS_VIRTUAL_HOSTING", bool_str(self.s3.virtual_hosting));
+        for (key, value) in [
+            ("AWS_S3_ENDPOINT", &self.s3.endpoint),
+            ("AWS_ACCESS_KEY_ID", &self.s3.access_key_id),
+            ("AWS_SECRET_ACCESS_KEY", &self.s3.secret_access_key),
+            ("AWS_REGION", &self.s3.region),
+        ] {
+            if let Some(value) = value {
+                set(key, value);
+            }
+        }
+        options.extend(self.gdal.clone());
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dev_config() {
+        let config = Config::from_toml(include_str!("../config/dev.toml")).unwrap();
+        let options = config.gdal_options();
+        assert_eq!(options["AWS_S3_ENDPOINT"], "localhost:9000");
+        assert_eq!(options["AWS_HTTPS"], "FALSE");
+        assert_eq!(options["VSI_CACHE"], "TRUE");
+        assert!(matches!(config.tile_cache, TileCacheConfig::Memory { .. }));
+
+        assert!(Config::from_toml("[server]\nprot = 8080").is_err());
+    }
+
+    #[test]
+    fn test_cli_overrides() {
+        let cli = Cli::parse_from([
+            "tilemachine",
+            "--port",
+            "9090",
+            "--s3-region",
+            "eu-central-1",
+            "--gdal-option",
+            "GDAL_CACHEMAX=512",
+        ]);
+        let config = Config::load(cli).unwrap();
+        assert_eq!(config.server.port, 9090);
+        assert_eq!(config.server.bind, "0.0.0.0");
+        let options = config.gdal_options();
+        assert_eq!(options["AWS_REGION"], "eu-central-1");
+        assert_eq!(options["GDAL_CACHEMAX"], "512");
+        assert_eq!(options["AWS_HTTPS"], "TRUE");
+    }
+}
@@ -1,48 +1,428 @@
 use crate::bbox::BoundingBox;
+use crate::ds_utils::{ReprojectOptions, Resampling};
 use crate::geojson::PolygonGeometry;
 use crate::source::Source;
 use crate::utils::ImageData;
-use crate::utils::ScriptError;
 use crate::utils::{Error, Result};
-use crate::xyz::{extract_tile, TileCoords, TILE_SIZE};
-use serde::Deserialize;
-use std::collections::HashMap;
+use crate::utils::{ScriptError, ScriptException};
+use crate::window::{extract_window, Window};
+use crate::xyz::{tile_window, TileCoords};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
 use std::sync::OnceLock;
 use v8::Message;
 
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ScriptMode {
+    /// The script is called for each pixel with, for each input, an array of the pixel's band
+    /// values, or null if the input has no valid data at this pixel. It returns an array with
+    /// the values of the pixel: [gray], [gray, alpha], [r, g, b] or [r, g, b, a] for RGBA
+    /// output, or one value per band when the script declares a float32 `output`
+    #[default]
+    Pixel,
+    /// The script is called once for the whole tile with, for each input, an array containing
+    /// one Float64Array per band (in row-major order), where invalid pixels are NaN. It returns
+    /// the values of all the pixels, channels last: a Uint8ClampedArray of RGBA values, or a
+    /// Float32Array with one value per band of the declared `output`
+    Tile,
+}
+
+/// An input of a script: the path of its source (see `open_source`) and how to warp it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "InputSpec")]
+pub struct Input {
+    pub path: String,
+    pub resampling: Resampling,
+    /// The bands passed to the script, in this order. All the bands of the source if None
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bands: Option<Vec<InputBand>>,
+}
+
+/// A (1-based) band of the source, optionally named so the script can access it as
+/// `input.name` in addition to `input[index]`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(from = "InputBandSpec")]
+pub struct InputBand {
+    pub band: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl Input {
+    pub fn reproject_options(&self) -> ReprojectOptions {
+        ReprojectOptions {
+            resampling: self.resampling,
+            bands: self
+                .bands
+                .as_ref()
+                .map(|bands| bands.iter().map(|b| b.band).collect()),
+            ..Default::default()
+        }
+    }
+
+    /// The names of the named bands, with their index in the array passed to the script
+    pub fn band_names(&self) -> Vec<(usize, String)> {
+        self.bands
+            .iter()
+            .flatten()
+            .enumerate()
+            .filter_map(|(index, band)| band.name.clone().map(|name| (index, name)))
+            .collect()
+    }
+}
+
+// Inputs are given either as a plain path or as an object with the path and options, e.g.
+// `{"path": "s3:s2.tif", "resampling": "nearest", "bands": [4, {"band": 8, "name": "nir"}]}`
 #[derive(Deserialize)]
+#[serde(untagged)]
+enum InputSpec {
+    Path(String),
+    Options {
+        path: String,
+        #[serde(default)]
+        resampling: Resampling,
+        #[serde(default)]
+        bands: Option<Vec<InputBand>>,
+    },
+}
+
+impl From<InputSpec> for Input {
+    fn from(spec: InputSpec) -> Self {
+        match spec {
+            InputSpec::Path(path) => Input {
+                path,
+                resampling: Resampling::default(),
+                bands: None,
+            },
+            InputSpec::Options {
+                path,
+                resampling,
+                bands,
+            } => Input {
+                path,
+                resampling,
+                bands,
+            },
+        }
+    }
+}
+
+// Bands are given either as a plain band number or as an object with the band number and name
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InputBandSpec {
+    Band(usize),
+    Named {
+        band: usize,
+        #[serde(default)]
+        name: Option<String>,
+    },
+}
+
+impl From<InputBandSpec> for InputBand {
+    fn from(spec: InputBandSpec) -> Self {
+        match spec {
+            InputBandSpec::Band(band) => InputBand { band, name: None },
+            InputBandSpec::Named { band, name } => InputBand { band, name },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputType {
+    Float32,
+}
+
+/// Declares that the script returns real values (e.g. NDVI or heights) instead of RGBA colors.
+/// In pixel mode, the script then returns an array of `bands` numbers and in tile mode a
+/// Float32Array of all the pixels' bands. Pixels without any valid input are NaN
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptOutput {
+    pub bands: usize,
+    #[serde(rename = "type")]
+    pub data_type: OutputType,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ParamType {
+    Number,
+    /// A number without a fractional part
+    Integer,
+    Boolean,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum ParamValue {
+    Boolean(bool),
+    Number(f64),
+}
+
+/// The value of each parameter of a script for a request, by name
+pub type ParamValues = BTreeMap<String, ParamValue>;
+
+/// Prefix of the query parameters overriding the default value of a script parameter, e.g.
+/// `?param.threshold=0.4`
+pub const PARAM_QUERY_PREFIX: &str = "param.";
+
+/// The `param.*` pairs of a query, encoded (in a stable order) without the leading `?`. This is
+/// empty if there are none. Used to carry the script parameters over to the URLs advertised to
+/// clients in capabilities and metadata documents
+pub fn params_query_string(query: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<(&String, &String)> = query
+        .iter()
+        .filter(|(key, _)| key.starts_with(PARAM_QUERY_PREFIX))
+        .collect();
+    pairs.sort();
+    url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(pairs)
+        .finish()
+}
+
+/// A parameter of the script, which it accesses as a global variable. Its value can be set for
+/// each request (see CustomScript::resolve_params), for example from a slider in a web client.
+/// E.g. `{"type": "number", "default": 0.3, "min": -1, "max": 1}`
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Param {
+    #[serde(rename = "type")]
+    pub param_type: ParamType,
+    pub default: ParamValue,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<f64>,
+}
+
+impl Param {
+    // Checks that the value has the type of the parameter and is within its bounds
+    fn check(&self, name: &str, value: ParamValue) -> Result<ParamValue> {
+        let invalid = |message: String| {
+            Err(Error::InvalidParameter(format!(
+                "parameter {}: {}",
+                name, message
+            )))
+        };
+        let number = match (self.param_type, value) {
+            (ParamType::Boolean, ParamValue::Boolean(_)) => return Ok(value),
+            (ParamType::Number, ParamValue::Number(n)) if n.is_finite() => n,
+            (ParamType::Integer, ParamValue::Number(n)) if n.is_finite() && n.fract() == 0.0 => n,
+            (ParamType::Boolean, _) => return invalid("expected a boolean".to_string()),
+            (ParamType::Number, _) => return invalid("expected a number".to_string()),
+            (ParamType::Integer, _) => return invalid("expected an integer".to_string()),
+        };
+        if self.min.is_some_and(|min| number < min) || self.max.is_some_and(|max| number > max) {
+            return invalid(format!(
+                "{} is outside of [{}, {}]",
+                number,
+                self.min.unwrap_or(f64::NEG_INFINITY),
+                self.max.unwrap_or(f64::INFINITY)
+            ));
+        }
+        Ok(value)
+    }
+
+    // Parses a value given in a query string
+    fn parse(&self, name: &str, value: &str) -> Result<ParamValue> {
+        let parsed = match self.param_type {
+            ParamType::Boolean => value.parse().ok().map(ParamValue::Boolean),
+            ParamType::Number | ParamType::Integer => value.parse().ok().map(ParamValue::Number),
+        };
+        match parsed {
+            Some(parsed) => self.check(name, parsed),
+            None => Err(Error::InvalidParameter(format!(
+                "parameter {}: invalid value {}",
+                name, value
+            ))),
+        }
+    }
+}
+
+// Whether `name` can be used as a JS variable name. This is stricter than JS, which also
+// allows `$` and non-ASCII letters
+fn is_identifier(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The image computed by a script
+pub enum OutputImage {
+    Rgba(ImageData<u8>),
+    Float32(ImageData<f32>),
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct CustomScript {
     script: String,
-    pub inputs: HashMap<String, String>,
+    pub inputs: HashMap<String, Input>,
+    #[serde(default)]
+    pub mode: ScriptMode,
+    /// RGBA colors if None
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<ScriptOutput>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub params: HashMap<String, Param>,
 }
 
 impl CustomScript {
     pub fn new_from_str(json_str: &str) -> Result<CustomScript> {
         let s: CustomScript = serde_json::from_str(json_str)?;
+        if let Some(output) = s.output {
+            if output.bands == 0 {
+                return Err(Error::InvalidParameter(
+                    "the output needs at least one band".to_string(),
+                ));
+            }
+        }
+        for (name, param) in s.params.iter() {
+            if !is_identifier(name) || s.inputs.contains_key(name) {
+                return Err(Error::InvalidParameter(format!(
+                    "invalid parameter name {}: must be a JS identifier and not an input",
+                    name
+                )));
+            }
+            if param.min.is_some() && param.max.is_some() && param.min > param.max {
+                return Err(Error::InvalidParameter(format!(
+                    "parameter {}: min is greater than max",
+                    name
+                )));
+            }
+            param.check(name, param.default)?;
+        }
         Ok(s)
     }
 
+    /// The values of the parameters for a request: those given in the query as
+    /// `param.{name}={value}`, and the defaults for the others
+    pub fn resolve_params(&self, query: &HashMap<String, String>) -> Result<ParamValues> {
+        let mut values: ParamValues = self
+            .params
+            .iter()
+            .map(|(name, param)| (name.clone(), param.default))
+            .collect();
+        for (key, value) in query.iter() {
+            if let Some(name) = key.strip_prefix(PARAM_QUERY_PREFIX) {
+                let param = self.params.get(name).ok_or_else(|| {
+                    Error::InvalidParameter(format!("unknown script parameter {}", name))
+                })?;
+                values.insert(name.to_string(), param.parse(name, value)?);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Whether the script returns real values rather than colors, see ScriptOutput
+    pub fn has_float_output(&self) -> bool {
+        self.output.is_some()
+    }
+
+    /// A compact JSON encoding of the script with sorted keys, which doesn't depend on how the
+    /// script was formatted by the client
+    pub fn to_canonical_json(&self) -> Result<String> {
+        // serde_json::Value sorts object keys
+        Ok(serde_json::to_value(self)?.to_string())
+    }
+
+    /// A hex-encoded SHA-256 of the canonical JSON encoding of the script
+    pub fn hash(&self) -> Result<String> {
+        let canonical = self.to_canonical_json()?;
+        Ok(format!("{:x}", Sha256::digest(canonical.as_bytes())))
+    }
+
+    /// Like hash, but also covers the values of the parameters, so it identifies what the script
+    /// renders for a request. This is the same as hash for scripts without parameters
+    pub fn hash_with_params(&self, params: &ParamValues) -> Result<String> {
+        if params.is_empty() {
+            return self.hash();
+        }
+        let canonical = self.to_canonical_json()? + &serde_json::to_string(params)?;
+        Ok(format!("{:x}", Sha256::digest(canonical.as_bytes())))
+    }
+
+    /// Run the script on the given XYZ tile, rendered at `tile_size` x `tile_size` pixels.
+    /// `params` are the values of the script parameters, see resolve_params
     pub fn execute_on_tile(
         &self,
         coords: &TileCoords,
+        tile_size: usize,
+        params: &ParamValues,
         open_source_fn: &dyn Fn(&str) -> Result<Box<dyn Source>>,
-    ) -> Result<ImageData<u8>> {
-        let mut engine = JSEngine::default();
-        let mut coll = ImageDataCollection::<f64>::new(TILE_SIZE as usize);
-        for (name, filename) in self.inputs.iter() {
-            let source = open_source_fn(filename)?;
-            let image_data = extract_tile(source.as_ref(), coords);
-            // Convert from u8 to f64 for computations
-            let data_f64 = image_data.data.to_vec();
-            let image_data = ImageData::from_vec(
-                image_data.width,
-                image_data.height,
-                image_data.channels,
-                data_f64,
-            );
+    ) -> Result<OutputImage> {
+        self.execute_on_window(&tile_window(coords, tile_size), params, open_source_fn)
+    }
+
+    /// Run the script on an arbitrary window (size and CRS), for example for a WMS GetMap
+    pub fn execute_on_window(
+        &self,
+        window: &Window,
+        params: &ParamValues,
+        open_source_fn: &dyn Fn(&str) -> Result<Box<dyn Source>>,
+    ) -> Result<OutputImage> {
+        match self.output {
+            None => Ok(OutputImage::Rgba(self.execute(
+                window,
+                4,
+                params,
+                open_source_fn,
+            )?)),
+            Some(ScriptOutput {
+                bands,
+                data_type: OutputType::Float32,
+            }) => Ok(OutputImage::Float32(self.execute(
+                window,
+                bands,
+                params,
+                open_source_fn,
+            )?)),
+        }
+    }
+
+    fn execute<T: OutputPixel>(
+        &self,
+        window: &Window,
+        num_bands: usize,
+        params: &ParamValues,
+        open_source_fn: &dyn Fn(&str) -> Result<Box<dyn Source>>,
+    ) -> Result<ImageData<T>> {
+        let mut coll = ImageDataCollection::<f64>::new(window.width, window.height);
+        coll.params = params.clone();
+        for (name, input) in self.inputs.iter() {
+            let source = open_source_fn(&input.path)?;
+            let (image_data, valid) =
+                extract_window(source.as_ref(), window, &input.reproject_options())?;
             coll.images.push((name.to_string(), image_data));
+            coll.masks.insert(name.to_string(), valid);
+            coll.band_names.insert(name.to_string(), input.band_names());
         }
-        engine.execute_on_tile(&self.script, &coll)
+        // Don't bother running the script if there's no data at all, e.g. outside of the inputs
+        let num_values = window.width * window.height * num_bands;
+        if !(0..window.width * window.height).any(|i| coll.any_valid(i)) {
+            let data = vec![T::nodata(); num_values];
+            return Ok(ImageData::from_vec(
+                window.width,
+                window.height,
+                num_bands,
+                data,
+            ));
+        }
+        ENGINE.with(|engine| {
+            let mut engine = engine.borrow_mut();
+            match self.mode {
+                ScriptMode::Pixel => engine.execute_on_tile(&self.script, &coll, num_bands),
+                ScriptMode::Tile => engine.execute_vectorized(&self.script, &coll, num_bands),
+            }
+        })
     }
 
     pub fn get_bounds(
@@ -50,14 +430,29 @@ impl CustomScript {
         open_source_fn: &dyn Fn(&str) -> Result<Box<dyn Source>>,
     ) -> Result<BoundingBox> {
         let mut bboxes: Vec<BoundingBox> = vec![];
-        for (_name, filename) in self.inputs.iter() {
-            let source = open_source_fn(filename)?;
+        for (_name, input) in self.inputs.iter() {
+            let source = open_source_fn(&input.path)?;
             bboxes.push(source.wgs84_bbox()?);
         }
 
         BoundingBox::union(&bboxes)
     }
 
+    /// The finest native resolution of the inputs (see Source::native_resolution), if known
+    pub fn native_resolution(
+        &self,
+        open_source_fn: &dyn Fn(&str) -> Result<Box<dyn Source>>,
+    ) -> Result<Option<f64>> {
+        let mut resolution: Option<f64> = None;
+        for (_name, input) in self.inputs.iter() {
+            let source = open_source_fn(&input.path)?;
+            if let Some(input_resolution) = source.native_resolution() {
+                resolution = Some(resolution.map_or(input_resolution, |r| r.min(input_resolution)));
+            }
+        }
+        Ok(resolution)
+    }
+
     pub fn get_bounds_as_polygon(
         &self,
         open_dataset_fn: &dyn Fn(&str) -> Result<Box<dyn Source>>,
@@ -68,7 +463,30 @@ impl CustomScript {
 
 static PLATFORM_INITIALIZED: OnceLock<bool> = OnceLock::new();
 
+// Maximum number of compiled functions kept by an engine. When reached, the least recently used
+// one is evicted
+const MAX_COMPILED_FUNCTIONS: usize = 64;
+
+thread_local! {
+    // Isolates cannot be shared between threads, so each worker thread gets its own engine,
+    // which lives (with its compiled functions cache) for as long as the thread
+    static ENGINE: RefCell<JSEngine> = RefCell::new(JSEngine::default());
+}
+
+struct CompiledFunction {
+    context: v8::Global<v8::Context>,
+    function: v8::Global<v8::Function>,
+    // The properties of the global object of the context right after it was created, see
+    // restore_globals
+    globals: Vec<(v8::Global<v8::Value>, v8::Global<v8::Value>)>,
+    global_names: HashSet<String>,
+}
+
 struct JSEngine {
+    // Compiled functions keyed by the SHA-256 of their code and argument names, see
+    // function_key. Note that this must be declared before the isolate so the globals are
+    // dropped before it
+    functions: LruCache<String, CompiledFunction>,
     isolate: v8::OwnedIsolate,
 }
 
@@ -77,7 +495,7 @@ impl Default for JSEngine {
         // Doing platform initialization twice seems to lead to "Invalid global state"
         // so it looks like we need a singleton to ensure this is done exactly once
         let initialized = PLATFORM_INITIALIZED.get_or_init(|| {
-            println!("initializing v8");
+            log::info!("initializing v8");
             let platform = v8::new_default_platform(0, false).make_shared();
             v8::V8::initialize_platform(platform);
             v8::V8::initialize();
@@ -88,56 +506,305 @@ impl Default for JSEngine {
         }
 
         let isolate = v8::Isolate::new(Default::default());
-        JSEngine { isolate }
+        JSEngine {
+            functions: LruCache::new(NonZeroUsize::new(MAX_COMPILED_FUNCTIONS).unwrap()),
+            isolate,
+        }
+    }
+}
+
+/// The types of the values scripts return
+pub trait OutputPixel: Default + Clone + Copy {
+    // Name of the typed array returned in tile mode
+    const ARRAY_TYPE: &'static str;
+    // Value of the pixels without any valid input
+    fn nodata() -> Self;
+    fn from_f64(v: f64) -> Self;
+    fn from_ne_bytes(bytes: &[u8]) -> Self;
+    fn is_output_array(value: &v8::Local<v8::Value>) -> bool;
+    // The lengths of the arrays a pixel mode script can return for an output of `num_bands`
+    fn accepted_lengths(num_bands: usize) -> RangeInclusive<usize>;
+    // Writes the values returned for a pixel, whose number is one of the accepted lengths
+    fn fill_pixel(values: &[f64], pixel: &mut [Self]);
+}
+
+impl OutputPixel for u8 {
+    const ARRAY_TYPE: &'static str = "Uint8ClampedArray";
+
+    fn nodata() -> Self {
+        0
+    }
+
+    fn from_f64(v: f64) -> Self {
+        // This saturates to [0, 255] and maps NaN to 0
+        v as u8
+    }
+
+    fn from_ne_bytes(bytes: &[u8]) -> Self {
+        bytes[0]
+    }
+
+    fn is_output_array(value: &v8::Local<v8::Value>) -> bool {
+        value.is_uint8_clamped_array()
+    }
+
+    fn accepted_lengths(_num_bands: usize) -> RangeInclusive<usize> {
+        1..=4
+    }
+
+    // Scripts can return gray, gray and alpha, RGB or RGBA values
+    fn fill_pixel(values: &[f64], pixel: &mut [Self]) {
+        let rgba = match *values {
+            [gray] => [gray, gray, gray, 255.0],
+            [gray, alpha] => [gray, gray, gray, alpha],
+            [r, g, b] => [r, g, b, 255.0],
+            [r, g, b, a] => [r, g, b, a],
+            _ => unreachable!("invalid pixel length {}", values.len()),
+        };
+        for (out, v) in pixel.iter_mut().zip(rgba) {
+            *out = Self::from_f64(v);
+        }
+    }
+}
+
+impl OutputPixel for f32 {
+    const ARRAY_TYPE: &'static str = "Float32Array";
+
+    fn nodata() -> Self {
+        f32::NAN
+    }
+
+    fn from_f64(v: f64) -> Self {
+        v as f32
+    }
+
+    fn from_ne_bytes(bytes: &[u8]) -> Self {
+        f32::from_ne_bytes(bytes.try_into().unwrap())
+    }
+
+    fn is_output_array(value: &v8::Local<v8::Value>) -> bool {
+        value.is_float32_array()
+    }
+
+    fn accepted_lengths(num_bands: usize) -> RangeInclusive<usize> {
+        num_bands..=num_bands
+    }
+
+    fn fill_pixel(values: &[f64], pixel: &mut [Self]) {
+        for (out, v) in pixel.iter_mut().zip(values) {
+            *out = Self::from_f64(*v);
+        }
+    }
+}
+
+// Maximum length of the returned values included in error messages
+const MAX_REPORTED_VALUE_LENGTH: usize = 100;
+
+// A readable representation of a value returned by a script, for error messages
+fn describe_value(value: v8::Local<v8::Value>, scope: &mut v8::HandleScope) -> String {
+    let description = if value.is_array() {
+        v8::json::stringify(scope, value).map(|json| json.to_rust_string_lossy(scope))
+    } else {
+        None
+    };
+    let description = description.unwrap_or_else(|| value.to_rust_string_lossy(scope));
+    if description.chars().count() > MAX_REPORTED_VALUE_LENGTH {
+        let truncated: String = description
+            .chars()
+            .take(MAX_REPORTED_VALUE_LENGTH)
+            .collect();
+        format!("{}...", truncated)
+    } else {
+        description
     }
 }
 
-fn run_on_pixel(
+// The named bands of an input, as (index in the input's array, name)
+type BandKeys<'s> = Vec<(usize, v8::Local<'s, v8::String>)>;
+
+// The band keys of each input, created once so they can be reused for all the pixels
+fn band_keys<'s>(
+    inputs: &ImageDataCollection<f64>,
+    scope: &mut v8::HandleScope<'s>,
+) -> Vec<BandKeys<'s>> {
+    inputs
+        .images
+        .iter()
+        .map(|(name, _)| {
+            inputs
+                .band_names
+                .get(name)
+                .into_iter()
+                .flatten()
+                .map(|(index, band_name)| (*index, v8::String::new(scope, band_name).unwrap()))
+                .collect()
+        })
+        .collect()
+}
+
+// Sets the script parameters as globals of the function's context. Since the context is kept
+// with the compiled function, this must be done on every call
+fn set_params(params: &ParamValues, scope: &mut v8::HandleScope) {
+    let global = scope.get_current_context().global(scope);
+    for (name, value) in params.iter() {
+        let key = v8::String::new(scope, name).unwrap();
+        let value: v8::Local<v8::Value> = match value {
+            ParamValue::Boolean(b) => v8::Boolean::new(scope, *b).into(),
+            ParamValue::Number(n) => v8::Number::new(scope, *n).into(),
+        };
+        global.set(scope, key.into(), value);
+    }
+}
+
+// Makes the named bands accessible as properties of the array, e.g. `s2.nir`
+fn set_band_aliases(array: v8::Local<v8::Array>, keys: &BandKeys, scope: &mut v8::HandleScope) {
+    for (index, key) in keys {
+        let value = array.get_index(scope, *index as u32).unwrap();
+        array.set(scope, (*key).into(), value);
+    }
+}
+
+// Calls the function on the pixel at (row, column) and writes the returned values to `output`
+fn run_on_pixel<'s, T: OutputPixel>(
     func: &v8::Local<v8::Function>,
-    args: Vec<(String, &[f64])>,
-    scope: &mut v8::HandleScope<'_>,
-) -> std::result::Result<[u8; 4], ScriptError> {
+    args: Vec<Option<&[f64]>>,
+    band_keys: &[BandKeys<'s>],
+    (row, column): (usize, usize),
+    output: &mut [T],
+    scope: &mut v8::HandleScope<'s>,
+) -> std::result::Result<(), ScriptError> {
     let call_scope = &mut v8::TryCatch::new(scope);
     let args: Vec<v8::Local<'_, v8::Value>> = args
         .iter()
-        .map(|(_name, pixel_values)| {
-            let elements: Vec<v8::Local<'_, v8::Value>> = pixel_values
+        .zip(band_keys)
+        .map(|(pixel_values, keys)| match pixel_values {
+            Some(pixel_values) => {
+                let elements: Vec<v8::Local<'_, v8::Value>> = pixel_values
+                    .iter()
+                    .map(|v| v8::Number::new(call_scope, *v).into())
+                    .collect();
+                let array = v8::Array::new_with_elements(call_scope, &elements[..]);
+                set_band_aliases(array, keys, call_scope);
+                array.into()
+            }
+            None => v8::null(call_scope).into(),
+        })
+        .collect();
+    let function_this: v8::Local<'_, v8::Value> = v8::null(call_scope).into();
+    if let Some(return_value) = func.call(call_scope, function_this, &args) {
+        let accepted_lengths = T::accepted_lengths(output.len());
+        let values: Option<Vec<f64>> = v8::Local::<v8::Array>::try_from(return_value)
+            .ok()
+            .filter(|array| accepted_lengths.contains(&(array.length() as usize)))
+            .and_then(|array| {
+                (0..array.length())
+                    .map(|i| {
+                        array
+                            .get_index(call_scope, i)
+                            .filter(|v| v.is_number())
+                            .and_then(|v| v.number_value(call_scope))
+                    })
+                    .collect()
+            });
+        match values {
+            Some(values) => {
+                T::fill_pixel(&values, output);
+                Ok(())
+            }
+            None => {
+                let expected = if accepted_lengths.start() == accepted_lengths.end() {
+                    format!("an array of {} numbers", accepted_lengths.start())
+                } else {
+                    format!(
+                        "an array of {} to {} numbers",
+                        accepted_lengths.start(),
+                        accepted_lengths.end()
+                    )
+                };
+                Err(ScriptError::InvalidPixelValue {
+                    row,
+                    column,
+                    expected,
+                    value: describe_value(return_value, call_scope),
+                })
+            }
+        }
+    } else {
+        Err(ScriptError::RuntimeError(report_exception(call_scope)))
+    }
+}
+
+// Returns the bands of the image as an array of Float64Array
+fn bands_to_js<'s>(
+    image: &ImageData<f64>,
+    scope: &mut v8::HandleScope<'s>,
+) -> v8::Local<'s, v8::Array> {
+    let num_pixels = image.width * image.height;
+    let bands: Vec<v8::Local<'_, v8::Value>> = (0..image.channels)
+        .map(|band| {
+            let buffer = v8::ArrayBuffer::new(scope, num_pixels * std::mem::size_of::<f64>());
+            let bytes = image
+                .data
                 .iter()
-                .map(|v| v8::Number::new(call_scope, *v).into())
-                .collect();
-            v8::Array::new_with_elements(call_scope, &elements[..]).into()
+                .skip(band)
+                .step_by(image.channels)
+                .flat_map(|v| v.to_ne_bytes());
+            for (cell, byte) in buffer.get_backing_store().iter().zip(bytes) {
+                cell.set(byte);
+            }
+            v8::Float64Array::new(scope, buffer, 0, num_pixels)
+                .unwrap()
+                .into()
+        })
+        .collect();
+    v8::Array::new_with_elements(scope, &bands[..])
+}
+
+fn run_on_tile<T: OutputPixel>(
+    func: &v8::Local<v8::Function>,
+    inputs: &ImageDataCollection<f64>,
+    output: &mut [T],
+    scope: &mut v8::HandleScope<'_>,
+) -> std::result::Result<(), ScriptError> {
+    let call_scope = &mut v8::TryCatch::new(scope);
+    let keys = band_keys(inputs, call_scope);
+    let args: Vec<v8::Local<'_, v8::Value>> = inputs
+        .images
+        .iter()
+        .zip(&keys)
+        .map(|((_name, image), keys)| {
+            let array = bands_to_js(image, call_scope);
+            set_band_aliases(array, keys, call_scope);
+            array.into()
         })
         .collect();
     let function_this: v8::Local<'_, v8::Value> = v8::null(call_scope).into();
     if let Some(return_value) = func.call(call_scope, function_this, &args) {
-        if !return_value.is_array() {
+        if !T::is_output_array(&return_value) {
             log::error!(
-                "Expected an array as return type, got {:?}",
+                "Expected a {} as return type, got {:?}",
+                T::ARRAY_TYPE,
                 return_value.type_repr()
             );
             return Err(ScriptError::InvalidReturnType);
         }
-        let return_array = v8::Local::<v8::Array>::try_from(return_value).unwrap();
-        let mut extract_channel = |i: u32| -> u8 {
-            let mut v = return_array
-                .get_index(call_scope, i)
-                .unwrap()
-                .number_value(call_scope)
-                .unwrap();
-            if v > 255.0 {
-                v = 255.0;
-            } else if v < 0.0 {
-                v = 0.0;
-            }
-            v as u8
-        };
-        let out = [
-            extract_channel(0),
-            extract_channel(1),
-            extract_channel(2),
-            extract_channel(3),
-        ];
-        Ok(out)
+        let return_array = v8::Local::<v8::TypedArray>::try_from(return_value).unwrap();
+        let value_size = std::mem::size_of::<T>();
+        if return_array.byte_length() != std::mem::size_of_val(output) {
+            log::error!(
+                "Expected a {} of length {}, got {}",
+                T::ARRAY_TYPE,
+                output.len(),
+                return_array.byte_length() / value_size
+            );
+            return Err(ScriptError::InvalidReturnType);
+        }
+        let mut bytes = vec![0; return_array.byte_length()];
+        return_array.copy_contents(&mut bytes);
+        for (out, value_bytes) in output.iter_mut().zip(bytes.chunks_exact(value_size)) {
+            *out = T::from_ne_bytes(value_bytes);
+        }
+        Ok(())
     } else {
         Err(ScriptError::RuntimeError(report_exception(call_scope)))
     }
@@ -146,18 +813,14 @@ fn run_on_pixel(
 fn get_underline_source_line(
     try_catch: &mut v8::TryCatch<v8::HandleScope>,
     message: &v8::Local<'_, Message>,
-) -> String {
+) -> Option<String> {
     // Get source code line
     let source_line = message
-        .get_source_line(try_catch)
-        .map(|s| {
-            s.to_string(try_catch)
-                .unwrap()
-                .to_rust_string_lossy(try_catch)
-        })
-        .unwrap();
+        .get_source_line(try_catch)?
+        .to_string(try_catch)?
+        .to_rust_string_lossy(try_catch);
 
-    let mut out = "at line: \n".to_string() + &source_line + "\n";
+    let mut out = source_line + "\n";
 
     // Add wavy underline
     let start_column = message.get_start_column();
@@ -171,12 +834,10 @@ fn get_underline_source_line(
         out += "^";
     }
 
-    out += "\n";
-    out
+    Some(out)
 }
 
-// https://github.com/denoland/rusty_v8/blob/3ff89f41462baab9c0bd8eaf8d7b1f4503ab4a0e/examples/shell.rs#L168
-fn report_exception(try_catch: &mut v8::TryCatch<v8::HandleScope>) -> String {
+fn report_exception(try_catch: &mut v8::TryCatch<v8::HandleScope>) -> ScriptException {
     let exception = try_catch.exception().unwrap();
     let exception_string = exception
         .to_string(try_catch)
@@ -186,17 +847,125 @@ fn report_exception(try_catch: &mut v8::TryCatch<v8::HandleScope>) -> String {
     let message = if let Some(message) = try_catch.message() {
         message
     } else {
-        return exception_string;
+        return ScriptException {
+            message: exception_string,
+            line: None,
+            column: None,
+            source_line: None,
+        };
     };
 
-    let line_number = message.get_line_number(try_catch).unwrap_or_default();
-    let mut out = format!("script:{}: {}\n", line_number, exception_string);
-    out += &get_underline_source_line(try_catch, &message);
-    out
+    ScriptException {
+        message: exception_string,
+        line: message.get_line_number(try_catch),
+        // V8 columns are 0-based
+        column: Some(message.get_start_column() + 1),
+        source_line: get_underline_source_line(try_catch, &message),
+    }
+}
+
+// A hex-encoded SHA-256 of everything the compiled function depends on, like
+// CustomScript::hash. Each part is prefixed with its length so that they can't run together
+fn function_key(code: &str, args_names: &[&String]) -> String {
+    let mut hasher = Sha256::new();
+    for part in std::iter::once(code).chain(args_names.iter().map(|name| name.as_str())) {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+// All the own properties of the global object, including the non-enumerable builtins
+fn global_property_names<'s>(
+    global: v8::Local<v8::Object>,
+    scope: &mut v8::HandleScope<'s>,
+) -> Vec<v8::Local<'s, v8::Value>> {
+    let args = v8::GetPropertyNamesArgs {
+        property_filter: v8::PropertyFilter::ALL_PROPERTIES | v8::PropertyFilter::SKIP_SYMBOLS,
+        ..Default::default()
+    };
+    let names = match global.get_own_property_names(scope, args) {
+        Some(names) => names,
+        None => return vec![],
+    };
+    (0..names.length())
+        .filter_map(|i| names.get_index(scope, i))
+        .collect()
+}
+
+// Undoes the changes a previous call made to the global object (e.g. `count = 0` without a
+// declaration, or overwriting a builtin), so the output of a script never depends on what it
+// rendered before. Mutations inside builtins (e.g. `Math.foo = 1`) are not undone
+fn restore_globals(compiled: &CompiledFunction, scope: &mut v8::HandleScope) {
+    let global = scope.get_current_context().global(scope);
+    for name in global_property_names(global, scope) {
+        if !compiled
+            .global_names
+            .contains(&name.to_rust_string_lossy(scope))
+        {
+            global.delete(scope, name);
+        }
+    }
+    for (name, value) in compiled.globals.iter() {
+        let name = v8::Local::new(scope, name);
+        let value = v8::Local::new(scope, value);
+        let current = global.get(scope, name);
+        if !current.is_some_and(|current| current.strict_equals(value)) {
+            global.set(scope, name, value);
+        }
+    }
+}
+
+fn compile_function(
+    handle_scope: &mut v8::HandleScope<'_, ()>,
+    code: &str,
+    args_names: &[&String],
+) -> std::result::Result<CompiledFunction, ScriptError> {
+    // Each function gets its own context, which is kept alive with it
+    let context = v8::Context::new(handle_scope);
+    let scope = &mut v8::ContextScope::new(handle_scope, context);
+    let global = context.global(scope);
+    let mut globals = vec![];
+    let mut global_names = HashSet::new();
+    for name in global_property_names(global, scope) {
+        if let Some(value) = global.get(scope, name) {
+            global_names.insert(name.to_rust_string_lossy(scope));
+            globals.push((v8::Global::new(scope, name), v8::Global::new(scope, value)));
+        }
+    }
+    // See the official example where they use a TryCatch as a scope
+    // https://github.com/denoland/rusty_v8/blob/3ff89f41462baab9c0bd8eaf8d7b1f4503ab4a0e/examples/shell.rs#L119
+    let scope = &mut v8::TryCatch::new(scope);
+    let code = v8::String::new(scope, code).unwrap();
+
+    let arg_names: Vec<v8::Local<'_, v8::String>> = args_names
+        .iter()
+        .map(|name| v8::String::new(scope, name).unwrap())
+        .collect();
+
+    if let Some(function) = v8::script_compiler::compile_function(
+        scope,
+        v8::script_compiler::Source::new(code, None),
+        &arg_names,
+        &[],
+        v8::script_compiler::CompileOptions::NoCompileOptions,
+        v8::script_compiler::NoCacheReason::NoReason,
+    ) {
+        Ok(CompiledFunction {
+            context: v8::Global::new(scope, context),
+            function: v8::Global::new(scope, function),
+            globals,
+            global_names,
+        })
+    } else {
+        Err(ScriptError::CompilationError(report_exception(scope)))
+    }
 }
 
 impl JSEngine {
-    fn compile_function<F>(
+    /// Calls `callback` with the compiled function, compiling it only if it isn't already
+    /// in the cache. The global object is restored to its initial state before each call
+    fn with_function<F>(
         &mut self,
         code: &str,
         args_names: Vec<&String>,
@@ -205,68 +974,71 @@ impl JSEngine {
     where
         F: FnMut(&v8::Local<v8::Function>, &mut v8::HandleScope<'_>),
     {
-        let handle_scope = &mut v8::HandleScope::new(&mut self.isolate);
-        let context = v8::Context::new(handle_scope);
-        let scope = &mut v8::ContextScope::new(handle_scope, context);
-        // See the official example where they use a TryCatch as a scope
-        // https://github.com/denoland/rusty_v8/blob/3ff89f41462baab9c0bd8eaf8d7b1f4503ab4a0e/examples/shell.rs#L119
-        let scope = &mut v8::TryCatch::new(scope);
-        let code = v8::String::new(scope, code).unwrap();
-
-        let arg_names: Vec<v8::Local<'_, v8::String>> = args_names
-            .iter()
-            .map(|name| v8::String::new(scope, name).unwrap())
-            .collect();
-
-        if let Some(function) = v8::script_compiler::compile_function(
-            scope,
-            v8::script_compiler::Source::new(code, None),
-            &arg_names,
-            &[],
-            v8::script_compiler::CompileOptions::NoCompileOptions,
-            v8::script_compiler::NoCacheReason::NoReason,
-        ) {
-            callback(&function, scope);
-            Ok(())
-        } else {
-            Err(ScriptError::CompilationError(report_exception(scope)))
+        let key = function_key(code, &args_names);
+        let JSEngine { functions, isolate } = self;
+        let handle_scope = &mut v8::HandleScope::new(isolate);
+        if !functions.contains(&key) {
+            let compiled = compile_function(handle_scope, code, &args_names)?;
+            functions.put(key.clone(), compiled);
         }
+        let compiled = functions.get(&key).unwrap();
+        let context = v8::Local::new(handle_scope, &compiled.context);
+        let scope = &mut v8::ContextScope::new(handle_scope, context);
+        restore_globals(compiled, scope);
+        let function = v8::Local::new(scope, &compiled.function);
+        callback(&function, scope);
+        Ok(())
     }
 
-    pub fn execute_on_tile(
+    /// Calls the function on each pixel, which returns `num_bands` values
+    pub fn execute_on_tile<T: OutputPixel>(
         &mut self,
         code: &str,
         inputs: &ImageDataCollection<f64>,
-    ) -> Result<ImageData<u8>> {
+        num_bands: usize,
+    ) -> Result<ImageData<T>> {
         let arg_names: Vec<&String> = inputs.images.iter().map(|(name, _data)| name).collect();
-        let mut output = ImageData::<u8>::new(inputs.tile_size, inputs.tile_size, 4);
-        // A bit of gymnastics to extract the error from the callback passed to compile_function
+        let num_values = inputs.width * inputs.height * num_bands;
+        let mut output = ImageData::from_vec(
+            inputs.width,
+            inputs.height,
+            num_bands,
+            vec![T::nodata(); num_values],
+        );
+        // A bit of gymnastics to extract the error from the callback passed to with_function
         let mut error: Option<Error> = None;
-        let result = self.compile_function(code, arg_names, &mut |function, scope| {
+        let result = self.with_function(code, arg_names, &mut |function, scope| {
+            set_params(&inputs.params, scope);
+            let keys = band_keys(inputs, scope);
             for i in 0..output.height {
                 for j in 0..output.width {
-                    let mut args: Vec<(String, &[f64])> = vec![];
+                    let pixel_index = i * output.width + j;
+                    // Leave the pixel transparent (or NaN)
+                    if !inputs.any_valid(pixel_index) {
+                        continue;
+                    }
+                    let mut args: Vec<Option<&[f64]>> = vec![];
                     for (name, image) in inputs.images.iter() {
+                        if !inputs.is_valid(name, pixel_index) {
+                            args.push(None);
+                            continue;
+                        }
                         let start_index = i * image.width * image.channels + j * image.channels;
                         let end_index = start_index + image.channels;
                         let val = &image.data[start_index..end_index];
-                        args.push((name.clone(), val));
+                        args.push(Some(val));
                     }
-                    match run_on_pixel(function, args, scope) {
-                        Ok(out_val) => {
-                            let out_start_index =
-                                i * output.width * output.channels + j * output.channels;
-                            output.data[out_start_index] = out_val[0];
-                            output.data[out_start_index + 1] = out_val[1];
-                            output.data[out_start_index + 2] = out_val[2];
-                            output.data[out_start_index + 3] = out_val[3];
-                        }
-                        Err(e) => error = Some(Error::ScriptError(e)),
+                    let out_start_index = pixel_index * num_bands;
+                    let out = &mut output.data[out_start_index..out_start_index + num_bands];
+                    if let Err(e) = run_on_pixel(function, args, &keys, (i, j), out, scope) {
+                        // Report the first failing pixel
+                        error = Some(Error::ScriptError(e));
+                        return;
                     }
                 }
             }
         });
-        // This handles runtime errors (from within the compile_function callback)
+        // This handles runtime errors (from within the with_function callback)
         if let Some(e) = error {
             return Err(e);
         }
@@ -276,21 +1048,80 @@ impl JSEngine {
             Err(e) => Err(Error::ScriptError(e)),
         }
     }
+
+    /// Like execute_on_tile, but calls the function once with all the pixels of the tile
+    /// (see ScriptMode::Tile)
+    pub fn execute_vectorized<T: OutputPixel>(
+        &mut self,
+        code: &str,
+        inputs: &ImageDataCollection<f64>,
+        num_bands: usize,
+    ) -> Result<ImageData<T>> {
+        let arg_names: Vec<&String> = inputs.images.iter().map(|(name, _data)| name).collect();
+        let mut output = ImageData::<T>::new(inputs.width, inputs.height, num_bands);
+        let mut error: Option<Error> = None;
+        let result = self.with_function(code, arg_names, &mut |function, scope| {
+            set_params(&inputs.params, scope);
+            if let Err(e) = run_on_tile(function, inputs, &mut output.data, scope) {
+                error = Some(Error::ScriptError(e));
+            }
+        });
+        if let Some(e) = error {
+            return Err(e);
+        }
+        result.map_err(Error::ScriptError)?;
+        // Make pixels without any valid input transparent (or NaN), whatever the script returned
+        for (pixel_index, pixel) in output.data.chunks_exact_mut(num_bands).enumerate() {
+            if !inputs.any_valid(pixel_index) {
+                pixel.fill(T::nodata());
+            }
+        }
+        Ok(output)
+    }
 }
 
 pub struct ImageDataCollection<T> {
     // We use a vector and not a hashmap here to guarantee ordering
     pub images: Vec<(String, ImageData<T>)>,
-    pub tile_size: usize,
+    // Validity of each pixel (in row-major order) by input name. Inputs without a mask are
+    // valid everywhere
+    pub masks: HashMap<String, Vec<bool>>,
+    // Named bands by input name, see Input::band_names
+    pub band_names: HashMap<String, Vec<(usize, String)>>,
+    // Values of the script parameters, set as globals
+    pub params: ParamValues,
+    pub width: usize,
+    pub height: usize,
 }
 
 impl<T> ImageDataCollection<T> {
-    pub fn new(tile_size: usize) -> ImageDataCollection<T> {
+    pub fn new(width: usize, height: usize) -> ImageDataCollection<T> {
         ImageDataCollection {
             images: vec![],
-            tile_size,
+            masks: HashMap::new(),
+            band_names: HashMap::new(),
+            params: ParamValues::new(),
+            width,
+            height,
         }
     }
+
+    pub fn is_valid(&self, name: &str, pixel_index: usize) -> bool {
+        match self.masks.get(name) {
+            Some(valid) => valid[pixel_index],
+            None => true,
+        }
+    }
+
+    /// Whether at least one of the inputs is valid at the given pixel. This is true everywhere
+    /// if there are no inputs
+    pub fn any_valid(&self, pixel_index: usize) -> bool {
+        self.images.is_empty()
+            || self
+                .images
+                .iter()
+                .any(|(name, _)| self.is_valid(name, pixel_index))
+    }
 }
 
 #[cfg(test)]
@@ -301,7 +1132,7 @@ mod tests {
     fn test_execute_on_tile_1() {
         let code = "return [3 * rgb[1], rgb[0], dsm[0]]";
         let mut engine = JSEngine::default();
-        let mut coll = ImageDataCollection::<f64>::new(2);
+        let mut coll = ImageDataCollection::<f64>::new(2, 2);
         coll.images.push((
             "rgb".to_owned(),
             ImageData::<f64>::from_vec(2, 2, 2, vec![0.0, 5.0, 4.0, 1.0, 3.0, 2.0, 7.0, 8.0]),
@@ -311,7 +1142,7 @@ mod tests {
             ImageData::<f64>::from_vec(2, 2, 1, vec![42.0, 43.0, 44.0, 45.0]),
         ));
 
-        let out_image = engine.execute_on_tile(code, &coll).unwrap();
+        let out_image = engine.execute_on_tile::<u8>(code, &coll, 4).unwrap();
         assert!(out_image.width == 2);
         assert!(out_image.height == 2);
         assert!(out_image.pixel_data(0, 0)[0] == 15);
@@ -321,4 +1152,431 @@ mod tests {
         assert!(out_image.pixel_data(1, 1)[1] == 7);
         assert!(out_image.pixel_data(1, 1)[2] == 45);
     }
+
+    #[test]
+    fn test_script_exception_position() {
+        let mut engine = JSEngine::default();
+        let mut coll = ImageDataCollection::<f64>::new(1, 1);
+        coll.images.push((
+            "dsm".to_owned(),
+            ImageData::<f64>::from_vec(1, 1, 1, vec![42.0]),
+        ));
+        match engine.execute_on_tile::<u8>("const a = 1;\nreturn [foo, 0, 0, 255]", &coll, 4) {
+            Err(Error::ScriptError(ScriptError::RuntimeError(e))) => {
+                assert_eq!(e.line, Some(2));
+                assert!(e.message.contains("foo"));
+            }
+            _ => panic!("Expected a runtime error"),
+        }
+        match engine.execute_on_tile::<u8>("return [", &coll, 4) {
+            Err(Error::ScriptError(ScriptError::CompilationError(e))) => {
+                assert_eq!(e.line, Some(1))
+            }
+            _ => panic!("Expected a compilation error"),
+        }
+    }
+
+    #[test]
+    fn test_execute_vectorized() {
+        let code = "
+            const out = new Uint8ClampedArray(dsm[0].length * 4);
+            for (let i = 0; i < dsm[0].length; i++) {
+                out.set([3 * rgb[1][i], rgb[0][i], dsm[0][i], 255], 4 * i);
+            }
+            return out;
+        ";
+        let mut engine = JSEngine::default();
+        let mut coll = ImageDataCollection::<f64>::new(2, 2);
+        coll.images.push((
+            "rgb".to_owned(),
+            ImageData::<f64>::from_vec(2, 2, 2, vec![0.0, 5.0, 4.0, 1.0, 3.0, 2.0, 7.0, 8.0]),
+        ));
+        coll.images.push((
+            "dsm".to_owned(),
+            ImageData::<f64>::from_vec(2, 2, 1, vec![42.0, 43.0, 44.0, 45.0]),
+        ));
+
+        let out_image = engine.execute_vectorized::<u8>(code, &coll, 4).unwrap();
+        assert!(out_image.pixel_data(0, 0) == [15, 0, 42, 255]);
+        assert!(out_image.pixel_data(1, 1) == [24, 7, 45, 255]);
+
+        // Returning a regular array or an array of the wrong size is an error
+        assert!(engine
+            .execute_vectorized::<u8>("return [0, 0, 0, 0]", &coll, 4)
+            .is_err());
+        assert!(engine
+            .execute_vectorized::<u8>("return new Uint8ClampedArray(4)", &coll, 4)
+            .is_err());
+    }
+
+    #[test]
+    fn test_invalid_pixels() {
+        let mut engine = JSEngine::default();
+        let mut coll = ImageDataCollection::<f64>::new(3, 1);
+        coll.images.push((
+            "a".to_owned(),
+            ImageData::<f64>::from_vec(3, 1, 1, vec![1.0, f64::NAN, f64::NAN]),
+        ));
+        coll.images.push((
+            "b".to_owned(),
+            ImageData::<f64>::from_vec(3, 1, 1, vec![2.0, 3.0, f64::NAN]),
+        ));
+        coll.masks.insert("a".to_owned(), vec![true, false, false]);
+        coll.masks.insert("b".to_owned(), vec![true, true, false]);
+
+        let code = "return [a === null ? 100 : a[0], b[0], 0, 255]";
+        let out_image = engine.execute_on_tile::<u8>(code, &coll, 4).unwrap();
+        assert_eq!(out_image.pixel_data(0, 0), &[1, 2, 0, 255]);
+        assert_eq!(out_image.pixel_data(0, 1), &[100, 3, 0, 255]);
+        // No valid input, the script isn't called
+        assert_eq!(out_image.pixel_data(0, 2), &[0, 0, 0, 0]);
+
+        let code = "
+            const out = new Uint8ClampedArray(3 * 4);
+            for (let i = 0; i < 3; i++) {
+                out.set([isNaN(a[0][i]) ? 100 : a[0][i], 0, 0, 255], 4 * i);
+            }
+            return out;
+        ";
+        let out_image = engine.execute_vectorized::<u8>(code, &coll, 4).unwrap();
+        assert_eq!(out_image.pixel_data(0, 0), &[1, 0, 0, 255]);
+        assert_eq!(out_image.pixel_data(0, 1), &[100, 0, 0, 255]);
+        assert_eq!(out_image.pixel_data(0, 2), &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_compiled_functions_cache() {
+        let mut engine = JSEngine::default();
+        let mut coll = ImageDataCollection::<f64>::new(1, 1);
+        coll.images.push((
+            "dsm".to_owned(),
+            ImageData::<f64>::from_vec(1, 1, 1, vec![42.0]),
+        ));
+        let code = "return [dsm[0], 0, 0, 255]";
+        engine.execute_on_tile::<u8>(code, &coll, 4).unwrap();
+        let out_image = engine.execute_on_tile::<u8>(code, &coll, 4).unwrap();
+        assert!(out_image.pixel_data(0, 0)[0] == 42);
+        assert!(engine.functions.len() == 1);
+
+        // Same code but different argument names should be compiled separately
+        coll.images[0].0 = "rgb".to_owned();
+        assert!(engine.execute_on_tile::<u8>(code, &coll, 4).is_err());
+        coll.images.push((
+            "dsm".to_owned(),
+            ImageData::<f64>::from_vec(1, 1, 1, vec![43.0]),
+        ));
+        let out_image = engine.execute_on_tile::<u8>(code, &coll, 4).unwrap();
+        assert!(out_image.pixel_data(0, 0)[0] == 43);
+        assert!(engine.functions.len() == 3);
+
+        // Only the least recently used function is evicted when the cache is full
+        let args = vec![&coll.images[0].0, &coll.images[1].0];
+        for i in 0..MAX_COMPILED_FUNCTIONS {
+            let other = format!("return [{}, 0, 0, 255]", i);
+            engine.execute_on_tile::<u8>(&other, &coll, 4).unwrap();
+            engine.execute_on_tile::<u8>(code, &coll, 4).unwrap();
+        }
+        assert_eq!(engine.functions.len(), MAX_COMPILED_FUNCTIONS);
+        assert!(engine.functions.contains(&function_key(code, &args)));
+        let first = format!("return [{}, 0, 0, 255]", 0);
+        assert!(!engine.functions.contains(&function_key(&first, &args)));
+
+        // The parts of the key can't run together
+        let (b, bc) = ("b".to_string(), "bc".to_string());
+        assert_ne!(function_key("a", &[&bc]), function_key("ab", &[&b]));
+    }
+
+    #[test]
+    fn test_globals_isolation() {
+        let mut engine = JSEngine::default();
+        let mut coll = ImageDataCollection::<f64>::new(1, 1);
+        coll.images.push((
+            "dsm".to_owned(),
+            ImageData::<f64>::from_vec(1, 1, 1, vec![42.0]),
+        ));
+        // Globals written by a call don't leak into the next ones
+        let code = "
+            count = typeof count === 'undefined' ? 1 : count + 1;
+            const v = Math.floor(dsm[0]);
+            Math = null;
+            return [v, count, 0, 255]
+        ";
+        for _ in 0..2 {
+            let out_image = engine.execute_on_tile::<u8>(code, &coll, 4).unwrap();
+            assert_eq!(out_image.pixel_data(0, 0), &[42, 1, 0, 255]);
+        }
+    }
+
+    #[test]
+    fn test_hash() {
+        let a = CustomScript::new_from_str(
+            r#"{"script": "return [1]", "inputs": {"a": "file:a.tif", "b": "file:b.tif"}}"#,
+        )
+        .unwrap();
+        let b = CustomScript::new_from_str(
+            r#"{"inputs": {"b": "file:b.tif", "a": "file:a.tif"}, "script": "return [1]"}"#,
+        )
+        .unwrap();
+        assert_eq!(a.hash().unwrap(), b.hash().unwrap());
+        let c = CustomScript::new_from_str(
+            r#"{"script": "return [2]", "inputs": {"a": "file:a.tif", "b": "file:b.tif"}}"#,
+        )
+        .unwrap();
+        assert_ne!(a.hash().unwrap(), c.hash().unwrap());
+        // A plain path is equivalent to an object with the default options
+        let d = CustomScript::new_from_str(
+            r#"{"script": "return [1]", "inputs": {"a": {"path": "file:a.tif"}, "b": "file:b.tif"}}"#,
+        )
+        .unwrap();
+        assert_eq!(a.hash().unwrap(), d.hash().unwrap());
+    }
+
+    #[test]
+    fn test_inputs() {
+        let script = CustomScript::new_from_str(
+            r#"{"script": "return [1]", "inputs": {
+                "rgb": "file:rgb.tif",
+                "landcover": {"path": "s3:landcover.tif", "resampling": "nearest"}
+            }}"#,
+        )
+        .unwrap();
+        assert_eq!(script.inputs["rgb"].path, "file:rgb.tif");
+        assert_eq!(script.inputs["rgb"].resampling, Resampling::Bilinear);
+        assert_eq!(script.inputs["landcover"].path, "s3:landcover.tif");
+        assert_eq!(script.inputs["landcover"].resampling, Resampling::Nearest);
+        assert_eq!(script.inputs["rgb"].reproject_options().bands, None);
+        assert!(CustomScript::new_from_str(
+            r#"{"script": "", "inputs": {"a": {"path": "file:a.tif", "resampling": "foo"}}}"#
+        )
+        .is_err());
+
+        let script = CustomScript::new_from_str(
+            r#"{"script": "", "inputs": {
+                "s2": {"path": "s3:s2.tif", "bands": [4, {"band": 8, "name": "nir"}]}
+            }}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            script.inputs["s2"].reproject_options().bands,
+            Some(vec![4, 8])
+        );
+        assert_eq!(
+            script.inputs["s2"].band_names(),
+            vec![(1, "nir".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_band_aliases() {
+        let mut engine = JSEngine::default();
+        let mut coll = ImageDataCollection::<f64>::new(1, 1);
+        coll.images.push((
+            "s2".to_owned(),
+            ImageData::<f64>::from_vec(1, 1, 2, vec![10.0, 20.0]),
+        ));
+        coll.band_names.insert(
+            "s2".to_owned(),
+            vec![(0, "red".to_string()), (1, "nir".to_string())],
+        );
+        let out_image = engine
+            .execute_on_tile::<u8>("return [s2.red, s2.nir, s2[1], 255]", &coll, 4)
+            .unwrap();
+        assert_eq!(out_image.pixel_data(0, 0), &[10, 20, 20, 255]);
+
+        let code = "
+            const out = new Uint8ClampedArray(4);
+            out.set([s2.red[0], s2.nir[0], 0, 255]);
+            return out;
+        ";
+        let out_image = engine.execute_vectorized::<u8>(code, &coll, 4).unwrap();
+        assert_eq!(out_image.pixel_data(0, 0), &[10, 20, 0, 255]);
+    }
+
+    #[test]
+    fn test_float_output() {
+        let mut engine = JSEngine::default();
+        let mut coll = ImageDataCollection::<f64>::new(2, 1);
+        coll.images.push((
+            "s2".to_owned(),
+            ImageData::<f64>::from_vec(2, 1, 2, vec![1.0, 3.0, f64::NAN, f64::NAN]),
+        ));
+        coll.masks.insert("s2".to_owned(), vec![true, false]);
+        let code = "return [(s2[1] - s2[0]) / (s2[1] + s2[0]), 1000]";
+        let out_image = engine.execute_on_tile::<f32>(code, &coll, 2).unwrap();
+        assert_eq!(out_image.pixel_data(0, 0), &[0.5, 1000.0]);
+        assert!(out_image.pixel_data(0, 1).iter().all(|v| v.is_nan()));
+
+        let code = "return new Float32Array([0.25, -2, 7, 8])";
+        let out_image = engine.execute_vectorized::<f32>(code, &coll, 2).unwrap();
+        assert_eq!(out_image.pixel_data(0, 0), &[0.25, -2.0]);
+        assert!(out_image.pixel_data(0, 1).iter().all(|v| v.is_nan()));
+        assert!(engine
+            .execute_vectorized::<f32>("return new Uint8ClampedArray(4)", &coll, 2)
+            .is_err());
+
+        let script = CustomScript::new_from_str(
+            r#"{"script": "", "inputs": {}, "output": {"bands": 1, "type": "float32"}}"#,
+        )
+        .unwrap();
+        assert!(script.has_float_output());
+        assert!(CustomScript::new_from_str(
+            r#"{"script": "", "inputs": {}, "output": {"bands": 0, "type": "float32"}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_pixel_values() {
+        let mut engine = JSEngine::default();
+        let mut coll = ImageDataCollection::<f64>::new(2, 1);
+        coll.images.push((
+            "dsm".to_owned(),
+            ImageData::<f64>::from_vec(2, 1, 1, vec![42.0, 43.0]),
+        ));
+        // Gray, gray and alpha, RGB and RGBA
+        for (code, expected) in [
+            ("return [dsm[0]]", [42, 42, 42, 255]),
+            ("return [dsm[0], 128]", [42, 42, 42, 128]),
+            ("return [dsm[0], 1, 2]", [42, 1, 2, 255]),
+            ("return [dsm[0], 1, 2, 3]", [42, 1, 2, 3]),
+        ] {
+            let out_image = engine.execute_on_tile::<u8>(code, &coll, 4).unwrap();
+            assert_eq!(out_image.pixel_data(0, 0), &expected);
+        }
+
+        let code = "return dsm[0] > 42 ? [0, 0, 0, 255, 255] : [0, 0, 0, 255]";
+        match engine.execute_on_tile::<u8>(code, &coll, 4) {
+            Err(Error::ScriptError(ScriptError::InvalidPixelValue {
+                row,
+                column,
+                expected,
+                value,
+            })) => {
+                assert_eq!((row, column), (0, 1));
+                assert_eq!(expected, "an array of 1 to 4 numbers");
+                assert_eq!(value, "[0,0,0,255,255]");
+            }
+            _ => panic!("Expected an invalid pixel value"),
+        }
+        for code in ["return []", "return [1, 'a', 0]", "return 42"] {
+            assert!(matches!(
+                engine.execute_on_tile::<u8>(code, &coll, 4),
+                Err(Error::ScriptError(ScriptError::InvalidPixelValue { .. }))
+            ));
+        }
+        // Float outputs must return exactly one value per band
+        assert!(engine
+            .execute_on_tile::<f32>("return [1]", &coll, 2)
+            .is_err());
+    }
+
+    #[test]
+    fn test_params() {
+        let script = CustomScript::new_from_str(
+            r#"{"script": "return [1]", "inputs": {"a": "file:a.tif"}, "params": {
+                "threshold": {"type": "number", "default": 0.3, "min": -1, "max": 1},
+                "steps": {"type": "integer", "default": 4, "min": 1},
+                "invert": {"type": "boolean", "default": false}
+            }}"#,
+        )
+        .unwrap();
+        let defaults = script.resolve_params(&HashMap::new()).unwrap();
+        assert_eq!(defaults["threshold"], ParamValue::Number(0.3));
+        assert_eq!(defaults["invert"], ParamValue::Boolean(false));
+
+        let query = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let params = script
+            .resolve_params(&query(&[
+                ("param.threshold", "-0.5"),
+                ("param.invert", "true"),
+                ("quality", "80"),
+            ]))
+            .unwrap();
+        assert_eq!(params["threshold"], ParamValue::Number(-0.5));
+        assert_eq!(params["steps"], ParamValue::Number(4.0));
+        assert_eq!(params["invert"], ParamValue::Boolean(true));
+        assert_eq!(
+            params_query_string(&query(&[
+                ("quality", "80"),
+                ("param.b", "a&b"),
+                ("param.a", "1")
+            ])),
+            "param.a=1&param.b=a%26b"
+        );
+        for invalid in [
+            ("param.threshold", "2"),
+            ("param.threshold", "abc"),
+            ("param.steps", "1.5"),
+            ("param.steps", "0"),
+            ("param.invert", "1"),
+            ("param.other", "1"),
+        ] {
+            assert!(matches!(
+                script.resolve_params(&query(&[invalid])),
+                Err(Error::InvalidParameter(_))
+            ));
+        }
+
+        // Parameters change the hash, but only if set
+        assert_eq!(
+            script.hash_with_params(&ParamValues::new()).unwrap(),
+            script.hash().unwrap()
+        );
+        assert_ne!(
+            script.hash_with_params(&defaults).unwrap(),
+            script.hash_with_params(&params).unwrap()
+        );
+
+        for invalid in [
+            r#"{"x": {"type": "number", "default": true}}"#,
+            r#"{"x": {"type": "number", "default": 2, "max": 1}}"#,
+            r#"{"x": {"type": "number", "default": 0, "min": 1, "max": -1}}"#,
+            r#"{"x": {"type": "integer", "default": 0.5}}"#,
+            r#"{"x": {"type": "number", "default": 0, "step": 1}}"#,
+            r#"{"a": {"type": "number", "default": 0}}"#,
+            r#"{"1x": {"type": "number", "default": 0}}"#,
+        ] {
+            let json = format!(
+                r#"{{"script": "return [1]", "inputs": {{"a": "file:a.tif"}}, "params": {}}}"#,
+                invalid
+            );
+            assert!(CustomScript::new_from_str(&json).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_params_globals() {
+        let mut engine = JSEngine::default();
+        let mut coll = ImageDataCollection::<f64>::new(1, 1);
+        coll.images.push((
+            "dsm".to_owned(),
+            ImageData::<f64>::from_vec(1, 1, 1, vec![42.0]),
+        ));
+        coll.params
+            .insert("threshold".to_string(), ParamValue::Number(40.0));
+        coll.params
+            .insert("invert".to_string(), ParamValue::Boolean(false));
+        let code = "return [dsm[0] > threshold !== invert ? 255 : 0, 0, 0, 255]";
+        let out_image = engine.execute_on_tile::<u8>(code, &coll, 4).unwrap();
+        assert_eq!(out_image.pixel_data(0, 0)[0], 255);
+        // The compiled function is reused with the new values
+        coll.params
+            .insert("invert".to_string(), ParamValue::Boolean(true));
+        let out_image = engine.execute_on_tile::<u8>(code, &coll, 4).unwrap();
+        assert_eq!(out_image.pixel_data(0, 0)[0], 0);
+        assert_eq!(engine.functions.len(), 1);
+
+        let code = "
+            const out = new Uint8ClampedArray(4);
+            out.set([threshold, 0, 0, 255]);
+            return out;
+        ";
+        let out_image = engine.execute_vectorized::<u8>(code, &coll, 4).unwrap();
+        assert_eq!(out_image.pixel_data(0, 0), &[40, 0, 0, 255]);
+    }
 }
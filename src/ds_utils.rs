@@ -1,10 +1,180 @@
-use crate::utils::ImageData;
-use gdal::raster::ResampleAlg;
-use gdal::Dataset;
+use crate::utils::{ImageData, Result};
+use crate::window::Window;
+use gdal::errors::GdalError;
+use gdal::raster::Buffer;
+use gdal::spatial_ref::SpatialRef;
+use gdal::{Dataset, DriverManager};
+use gdal_sys::{CPLErr, GDALResampleAlg, OSRAxisMappingStrategy};
+use serde::{Deserialize, Serialize};
+use std::ffi::{c_char, CStr, CString};
+use std::ptr::{null, null_mut};
+
+/// Resampling algorithm used when warping a source, named as in gdalwarp
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Resampling {
+    /// Best for categorical rasters (e.g. land cover, masks)
+    Nearest,
+    #[default]
+    Bilinear,
+    /// Smoother, e.g. for elevation models
+    Cubic,
+    CubicSpline,
+    Lanczos,
+    Average,
+    Mode,
+    Min,
+    Max,
+    Med,
+    Q1,
+    Q3,
+}
+
+impl Resampling {
+    fn to_gdal(self) -> GDALResampleAlg::Type {
+        match self {
+            Resampling::Nearest => GDALResampleAlg::GRA_NearestNeighbour,
+            Resampling::Bilinear => GDALResampleAlg::GRA_Bilinear,
+            Resampling::Cubic => GDALResampleAlg::GRA_Cubic,
+            Resampling::CubicSpline => GDALResampleAlg::GRA_CubicSpline,
+            Resampling::Lanczos => GDALResampleAlg::GRA_Lanczos,
+            Resampling::Average => GDALResampleAlg::GRA_Average,
+            Resampling::Mode => GDALResampleAlg::GRA_Mode,
+            Resampling::Min => GDALResampleAlg::GRA_Min,
+            Resampling::Max => GDALResampleAlg::GRA_Max,
+            Resampling::Med => GDALResampleAlg::GRA_Med,
+            Resampling::Q1 => GDALResampleAlg::GRA_Q1,
+            Resampling::Q3 => GDALResampleAlg::GRA_Q3,
+        }
+    }
+}
+
+/// How a source gets warped to the target window
+#[derive(Default, Clone, Debug)]
+pub struct ReprojectOptions {
+    pub resampling: Resampling,
+    /// The (1-based) bands of the source to warp, in the order of the target bands. All the
+    /// bands if None
+    pub bands: Option<Vec<usize>>,
+    /// Also warp the mask of the source (see `Source::has_mask`) into an extra last band
+    pub mask: bool,
+}
+
+// The error GDAL reported last, for functions that only return a status or a null pointer
+fn last_cpl_error(class: CPLErr::Type) -> GdalError {
+    let msg = unsafe { CStr::from_ptr(gdal_sys::CPLGetLastErrorMsg()) };
+    GdalError::CplError {
+        class,
+        number: unsafe { gdal_sys::CPLGetLastErrorNo() },
+        msg: msg.to_string_lossy().into_owned(),
+    }
+}
+
+/// A virtual (VRT) dataset exposing only the given (1-based) bands of `src`, followed by its
+/// mask band if `mask` is true. This doesn't read any pixel, and the returned dataset must not
+/// outlive `src`
+pub fn select_bands(src: &Dataset, bands: &[usize], mask: bool) -> Result<Dataset> {
+    let mut args = vec!["-of".to_string(), "VRT".to_string()];
+    for band in bands {
+        args.push("-b".to_string());
+        args.push(band.to_string());
+    }
+    if mask {
+        args.push("-b".to_string());
+        args.push("mask".to_string());
+    }
+    let args: Vec<CString> = args
+        .into_iter()
+        .map(|arg| CString::new(arg).unwrap())
+        .collect();
+    let mut argv: Vec<*mut c_char> = args.iter().map(|arg| arg.as_ptr() as *mut _).collect();
+    argv.push(null_mut());
+    let dst_name = CString::new("").unwrap();
+    let ds = unsafe {
+        let options = gdal_sys::GDALTranslateOptionsNew(argv.as_mut_ptr(), null_mut());
+        let mut usage_error = 0;
+        let ds = gdal_sys::GDALTranslate(
+            dst_name.as_ptr(),
+            src.c_dataset(),
+            options,
+            &mut usage_error,
+        );
+        gdal_sys::GDALTranslateOptionsFree(options);
+        ds
+    };
+    if ds.is_null() {
+        return Err(last_cpl_error(CPLErr::CE_Failure).into());
+    }
+    Ok(unsafe { Dataset::from_c_dataset(ds) })
+}
+
+/// Warp `src` into `dst`, both of which must be georeferenced
+pub fn reproject(src: &Dataset, dst: &Dataset, options: &ReprojectOptions) -> Result<()> {
+    if options.bands.is_some() || options.mask {
+        let all_bands: Vec<usize> = (1..=src.raster_count() as usize).collect();
+        let bands = options.bands.as_ref().unwrap_or(&all_bands);
+        let selected = select_bands(src, bands, options.mask)?;
+        let options = ReprojectOptions {
+            bands: None,
+            mask: false,
+            ..options.clone()
+        };
+        return reproject(&selected, dst, &options);
+    }
+    let rv = unsafe {
+        gdal_sys::GDALReprojectImage(
+            src.c_dataset(),
+            null(),
+            dst.c_dataset(),
+            null(),
+            options.resampling.to_gdal(),
+            0.0,
+            0.0,
+            None,
+            null_mut(),
+            null_mut(),
+        )
+    };
+    if rv != CPLErr::CE_None {
+        return Err(last_cpl_error(rv).into());
+    }
+    Ok(())
+}
+
+/// Create an in-memory dataset georeferenced on `window` and holding the given image data
+pub fn dataset_from_image(image: &ImageData<u8>, window: &Window) -> Result<Dataset> {
+    let srs = SpatialRef::from_epsg(window.epsg)?;
+    srs.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+    let drv = DriverManager::get_driver_by_name("MEM")?;
+    let mut ds = drv.create_with_band_type::<u8, _>(
+        "",
+        image.width as isize,
+        image.height as isize,
+        image.channels as isize,
+    )?;
+    ds.set_geo_transform(&window.geo_transform())?;
+    ds.set_spatial_ref(&srs)?;
+    for i in 0..image.channels {
+        let data: Vec<u8> = image
+            .data
+            .iter()
+            .skip(i)
+            .step_by(image.channels)
+            .copied()
+            .collect();
+        let mut band = ds.rasterband(i as isize + 1)?;
+        band.write(
+            (0, 0),
+            (image.width, image.height),
+            &Buffer::new((image.width, image.height), data),
+        )?;
+    }
+    Ok(ds)
+}
 
 // TODO: This require 'rasterIO' to be exposed on the Dataset, see
 // https://github.com/georust/gdal/pull/374
-pub fn read_ds_at_once(ds: &Dataset) -> ImageData<f64> {
+pub fn read_ds_at_once(ds: &Dataset) -> Result<ImageData<f64>> {
     let nbands = ds.raster_count() as usize;
     let size = ds.raster_size();
     let buf = ds
@@ -12,37 +182,31 @@ pub fn read_ds_at_once(ds: &Dataset) -> ImageData<f64> {
             (0, 0),
             (size.0, size.1),
             (size.0, size.1),
-            Some(ResampleAlg::Bilinear),
+            // This reads at the native resolution, so no resampling happens
+            None,
             gdal::ImageInterleaving::Pixel,
             gdal::BandSelection::All,
-        )
-        .unwrap()
+        )?
         .data;
-    ImageData::from_vec(size.0, size.1, nbands, buf)
+    Ok(ImageData::from_vec(size.0, size.1, nbands, buf))
 }
 
 // Reads the whole dataset into a buffer
 // Returns the buffer and its size (width, height)
 #[allow(dead_code)]
-pub fn read_ds_band_by_band(ds: &Dataset) -> ImageData<f64> {
+pub fn read_ds_band_by_band(ds: &Dataset) -> Result<ImageData<f64>> {
     let nbands = ds.raster_count() as usize;
     let size = ds.raster_size();
     let mut image_data = ImageData::<f64>::new(size.0, size.1, nbands);
     for i in 1..nbands + 1 {
-        let band = ds.rasterband(i as isize).unwrap();
+        let band = ds.rasterband(i as isize)?;
         let data = band
-            .read_as::<f64>(
-                (0, 0),
-                (size.0, size.1),
-                (size.0, size.1),
-                Some(ResampleAlg::Bilinear),
-            )
-            .unwrap()
+            .read_as::<f64>((0, 0), (size.0, size.1), (size.0, size.1), None)?
             .data;
         // Place pixels in buf
         for (j, e) in data.iter().enumerate() {
             image_data.data[j * nbands + (i - 1)] = *e;
         }
     }
-    image_data
+    Ok(image_data)
 }
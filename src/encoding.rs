@@ -0,0 +1,375 @@
+//! Encoding of rendered images in the formats served to clients. The format of a tile is chosen
+//! from its extension (`/12.jpg`) or, without extension, from the `Accept` header
+use crate::custom_script::OutputImage;
+use crate::utils::{Error, ImageData, Result};
+use crate::window::Window;
+use gdal::raster::{Buffer, GdalType, RasterCreationOption};
+use gdal::spatial_ref::SpatialRef;
+use gdal::DriverManager;
+use gdal_sys::OSRAxisMappingStrategy;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 85;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Png,
+    /// JPEG has no transparency, so transparent pixels end up black
+    Jpeg {
+        quality: u8,
+    },
+    /// Lossless if there is no quality
+    Webp {
+        quality: Option<u8>,
+    },
+    /// A Float32 GeoTIFF georeferenced on the rendered window
+    GeoTiff,
+    /// The values of a float output as little-endian Float32, in row-major order with the bands
+    /// of each pixel next to each other
+    Raw,
+}
+
+impl OutputFormat {
+    pub fn from_extension(extension: &str) -> Option<OutputFormat> {
+        match extension.to_lowercase().as_str() {
+            "png" => Some(OutputFormat::Png),
+            "jpg" | "jpeg" => Some(OutputFormat::Jpeg {
+                quality: DEFAULT_JPEG_QUALITY,
+            }),
+            "webp" => Some(OutputFormat::Webp { quality: None }),
+            "tif" | "tiff" => Some(OutputFormat::GeoTiff),
+            "bin" => Some(OutputFormat::Raw),
+            _ => None,
+        }
+    }
+
+    /// Parses a MIME type, ignoring its parameters (e.g. `image/tiff; application=geotiff`)
+    pub fn from_mime_type(mime_type: &str) -> Option<OutputFormat> {
+        let essence = mime_type.split(';').next().unwrap_or("").trim();
+        match essence.to_lowercase().as_str() {
+            "image/png" => Some(OutputFormat::Png),
+            "image/jpeg" => OutputFormat::from_extension("jpg"),
+            "image/webp" => OutputFormat::from_extension("webp"),
+            "image/tiff" => Some(OutputFormat::GeoTiff),
+            "application/octet-stream" => Some(OutputFormat::Raw),
+            _ => None,
+        }
+    }
+
+    /// The first format listed in an `Accept` header that supports the script output (see
+    /// `supports`). Quality values are ignored and wildcards don't match anything, so browsers
+    /// get their preferred format
+    pub fn from_accept(accept: &str, float_output: bool) -> Option<OutputFormat> {
+        accept
+            .split(',')
+            .filter_map(OutputFormat::from_mime_type)
+            .find(|format| format.supports(float_output))
+    }
+
+    /// Whether this format can encode RGBA colors or, if `float_output`, real values
+    pub fn supports(&self, float_output: bool) -> bool {
+        match self {
+            OutputFormat::GeoTiff => true,
+            OutputFormat::Raw => float_output,
+            _ => !float_output,
+        }
+    }
+
+    /// Sets the quality (between 1 and 100) of lossy formats. WebP becomes lossy
+    pub fn with_quality(self, quality: u8) -> Result<OutputFormat> {
+        if !(1..=100).contains(&quality) {
+            return Err(Error::InvalidParameter(format!(
+                "quality must be between 1 and 100, got {}",
+                quality
+            )));
+        }
+        match self {
+            OutputFormat::Jpeg { .. } => Ok(OutputFormat::Jpeg { quality }),
+            OutputFormat::Webp { .. } => Ok(OutputFormat::Webp {
+                quality: Some(quality),
+            }),
+            _ => Err(Error::InvalidParameter(format!(
+                "quality is not supported for {}",
+                self.mime_type()
+            ))),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg { .. } => "jpg",
+            OutputFormat::Webp { .. } => "webp",
+            OutputFormat::GeoTiff => "tif",
+            OutputFormat::Raw => "bin",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg { .. } => "image/jpeg",
+            OutputFormat::Webp { .. } => "image/webp",
+            OutputFormat::GeoTiff => "image/tiff; application=geotiff",
+            OutputFormat::Raw => "application/octet-stream",
+        }
+    }
+
+    /// Identifies the format with its options, e.g. `png` or `q85.jpg`, for use in cache keys
+    pub fn cache_name(&self) -> String {
+        match self {
+            OutputFormat::Jpeg { quality }
+            | OutputFormat::Webp {
+                quality: Some(quality),
+            } => format!("q{}.{}", quality, self.extension()),
+            _ => self.extension().to_string(),
+        }
+    }
+}
+
+/// The format of a tile: from its extension if there is one, otherwise from the `Accept` header,
+/// and by default PNG (GeoTIFF for float outputs). `quality` (a query parameter) applies to lossy
+/// formats
+pub fn select_format(
+    extension: Option<&str>,
+    accept: Option<&str>,
+    quality: Option<&str>,
+    float_output: bool,
+) -> Result<OutputFormat> {
+    let format = match extension {
+        Some(extension) => OutputFormat::from_extension(extension)
+            .filter(|format| format.supports(float_output))
+            .ok_or_else(|| Error::InvalidPath(format!("unsupported tile format: {}", extension)))?,
+        None => accept
+            .and_then(|accept| OutputFormat::from_accept(accept, float_output))
+            .unwrap_or(if float_output {
+                OutputFormat::GeoTiff
+            } else {
+                OutputFormat::Png
+            }),
+    };
+    match quality {
+        Some(quality) => {
+            let quality = quality
+                .parse::<u8>()
+                .map_err(|_| Error::InvalidParameter(format!("invalid quality: {}", quality)))?;
+            format.with_quality(quality)
+        }
+        None => Ok(format),
+    }
+}
+
+/// Encode the image rendered on `window` in the given format
+pub fn encode(image: &OutputImage, window: &Window, format: OutputFormat) -> Result<Vec<u8>> {
+    match (image, format) {
+        (OutputImage::Rgba(image), OutputFormat::Png) => image.to_png(),
+        (OutputImage::Rgba(image), OutputFormat::Jpeg { quality }) => encode_jpeg(image, quality),
+        (OutputImage::Rgba(image), OutputFormat::Webp { quality }) => {
+            Ok(encode_webp(image, quality))
+        }
+        (OutputImage::Rgba(image), OutputFormat::GeoTiff) => {
+            let data = image.data.iter().map(|v| *v as f32).collect();
+            let image = ImageData::from_vec(image.width, image.height, image.channels, data);
+            encode_geotiff(&image, window, None)
+        }
+        (OutputImage::Float32(image), OutputFormat::GeoTiff) => {
+            encode_geotiff(image, window, Some(f64::NAN))
+        }
+        (OutputImage::Float32(image), OutputFormat::Raw) => {
+            Ok(image.data.iter().flat_map(|v| v.to_le_bytes()).collect())
+        }
+        (_, format) => Err(Error::InvalidParameter(format!(
+            "{} is not supported for this script output",
+            format.mime_type()
+        ))),
+    }
+}
+
+fn encode_jpeg(image: &ImageData<u8>, quality: u8) -> Result<Vec<u8>> {
+    let (width, height) = match (u16::try_from(image.width), u16::try_from(image.height)) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => {
+            return Err(Error::EncodingError(format!(
+                "image too large for JPEG: {}x{}",
+                image.width, image.height
+            )))
+        }
+    };
+    let mut out = Vec::new();
+    jpeg_encoder::Encoder::new(&mut out, quality)
+        .encode(&image.data, width, height, jpeg_encoder::ColorType::Rgba)
+        .map_err(|e| Error::EncodingError(e.to_string()))?;
+    Ok(out)
+}
+
+fn encode_webp(image: &ImageData<u8>, quality: Option<u8>) -> Vec<u8> {
+    let encoder = webp::Encoder::from_rgba(&image.data, image.width as u32, image.height as u32);
+    let webp = match quality {
+        Some(quality) => encoder.encode(quality as f32),
+        None => encoder.encode_lossless(),
+    };
+    webp.to_vec()
+}
+
+/// Encode the image as a (deflate compressed) GeoTIFF, with one band per channel
+pub fn encode_geotiff<T: GdalType + Copy>(
+    image: &ImageData<T>,
+    window: &Window,
+    nodata: Option<f64>,
+) -> Result<Vec<u8>> {
+    // GDAL can only write GeoTIFFs to a file, so use a unique in-memory one
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = format!(
+        "/vsimem/tilemachine_{}.tif",
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    {
+        let srs = SpatialRef::from_epsg(window.epsg)?;
+        srs.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let options = [RasterCreationOption {
+            key: "COMPRESS",
+            value: "DEFLATE",
+        }];
+        let mut ds = DriverManager::get_driver_by_name("GTiff")?
+            .create_with_band_type_with_options::<T, _>(
+                &path,
+                image.width as isize,
+                image.height as isize,
+                image.channels as isize,
+                &options,
+            )?;
+        ds.set_geo_transform(&window.geo_transform())?;
+        ds.set_spatial_ref(&srs)?;
+        for i in 0..image.channels {
+            let data: Vec<T> = image
+                .data
+                .iter()
+                .skip(i)
+                .step_by(image.channels)
+                .copied()
+                .collect();
+            let mut band = ds.rasterband(i as isize + 1)?;
+            band.set_no_data_value(nodata)?;
+            band.write(
+                (0, 0),
+                (image.width, image.height),
+                &Buffer::new((image.width, image.height), data),
+            )?;
+        }
+        // The file is only complete once the dataset is closed
+    }
+    Ok(gdal::vsi::get_vsi_mem_file_bytes_owned(&path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbox::BoundingBox;
+
+    #[test]
+    fn test_output_format() {
+        assert_eq!(
+            OutputFormat::from_extension("JPG"),
+            Some(OutputFormat::Jpeg { quality: 85 })
+        );
+        assert_eq!(OutputFormat::from_extension("gif"), None);
+        assert_eq!(
+            OutputFormat::from_accept("image/avif,image/webp,*/*;q=0.8", false),
+            Some(OutputFormat::Webp { quality: None })
+        );
+        assert_eq!(OutputFormat::from_accept("*/*", false), None);
+        assert_eq!(
+            OutputFormat::from_accept("image/tiff; application=geotiff", false),
+            Some(OutputFormat::GeoTiff)
+        );
+        // Only formats supporting real values are considered for float outputs
+        assert_eq!(
+            OutputFormat::from_accept("image/png,application/octet-stream", true),
+            Some(OutputFormat::Raw)
+        );
+        let jpeg = OutputFormat::Jpeg { quality: 85 };
+        assert_eq!(jpeg.cache_name(), "q85.jpg");
+        assert_eq!(jpeg.with_quality(50).unwrap().cache_name(), "q50.jpg");
+        assert!(jpeg.with_quality(0).is_err());
+        assert!(OutputFormat::Png.with_quality(50).is_err());
+        assert_eq!(
+            OutputFormat::Webp { quality: None }.cache_name(),
+            "webp".to_string()
+        );
+    }
+
+    #[test]
+    fn test_select_format() {
+        let accept = Some("image/webp,*/*");
+        assert_eq!(
+            select_format(Some("png"), accept, None, false).unwrap(),
+            OutputFormat::Png
+        );
+        assert_eq!(
+            select_format(None, accept, Some("75"), false).unwrap(),
+            OutputFormat::Webp { quality: Some(75) }
+        );
+        assert_eq!(
+            select_format(None, None, None, false).unwrap(),
+            OutputFormat::Png
+        );
+        assert!(select_format(Some("gif"), None, None, false).is_err());
+        assert!(select_format(Some("jpg"), None, Some("high"), false).is_err());
+        // Float outputs
+        assert_eq!(
+            select_format(None, accept, None, true).unwrap(),
+            OutputFormat::GeoTiff
+        );
+        assert_eq!(
+            select_format(Some("bin"), None, None, true).unwrap(),
+            OutputFormat::Raw
+        );
+        assert!(select_format(Some("png"), None, None, true).is_err());
+        assert!(select_format(Some("bin"), None, None, false).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let image = OutputImage::Rgba(ImageData::from_vec(
+            2,
+            1,
+            4,
+            vec![255, 0, 0, 255, 0, 0, 255, 128],
+        ));
+        let window = Window {
+            bbox: BoundingBox {
+                xmin: 0.0,
+                ymin: 0.0,
+                xmax: 2.0,
+                ymax: 1.0,
+            },
+            epsg: 3857,
+            width: 2,
+            height: 1,
+        };
+        let jpeg = encode(&image, &window, OutputFormat::Jpeg { quality: 85 }).unwrap();
+        assert_eq!(&jpeg[..2], &[0xFF, 0xD8]);
+        let webp = encode(&image, &window, OutputFormat::Webp { quality: None }).unwrap();
+        assert_eq!(&webp[..4], b"RIFF");
+
+        let tiff = encode(&image, &window, OutputFormat::GeoTiff).unwrap();
+        gdal::vsi::create_mem_file("/vsimem/test_encode.tif", tiff).unwrap();
+        let ds = gdal::Dataset::open("/vsimem/test_encode.tif").unwrap();
+        assert_eq!(ds.raster_count(), 4);
+        assert_eq!(ds.geo_transform().unwrap(), window.geo_transform());
+        let band = ds.rasterband(4).unwrap();
+        assert_eq!(
+            band.read_as::<f32>((0, 0), (2, 1), (2, 1), None)
+                .unwrap()
+                .data,
+            vec![255.0, 128.0]
+        );
+        gdal::vsi::unlink_mem_file("/vsimem/test_encode.tif").unwrap();
+
+        let image = OutputImage::Float32(ImageData::from_vec(2, 1, 1, vec![0.5, f32::NAN]));
+        let raw = encode(&image, &window, OutputFormat::Raw).unwrap();
+        assert_eq!(&raw[..4], &0.5f32.to_le_bytes());
+        assert_eq!(raw.len(), 8);
+        assert!(encode(&image, &window, OutputFormat::Png).is_err());
+    }
+}
@@ -1,10 +1,20 @@
 pub mod bbox;
+pub mod config;
 pub mod custom_script;
 pub mod ds_utils;
+pub mod encoding;
 pub mod geojson;
+pub mod ogcapi;
 pub mod raster;
+pub mod render_pool;
+pub mod script_store;
+pub mod tile_cache;
+pub mod tilejson;
+pub mod tms;
 pub mod utils;
+pub mod window;
 pub mod wms;
+pub mod wmts;
 pub mod xyz;
 
 pub mod source;
@@ -1,59 +1,527 @@
-use std::env;
-
 use actix_files as fs;
 use actix_web::{
-    get, http::header::ContentType, middleware, web, App, HttpRequest, HttpResponse, HttpServer,
+    get,
+    http::{
+        header::{self, ContentType},
+        StatusCode,
+    },
+    middleware, post, web, App, HttpRequest, HttpResponse, HttpServer,
 };
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
-use tilemachine::xyz::TileCoords;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tilemachine::xyz::{parse_tile_x, TILE_SIZE};
 
-use tilemachine::custom_script::CustomScript;
-use tilemachine::source::open_source;
+use tilemachine::bbox::BoundingBox;
+use tilemachine::config::{Cli, Config, ScriptStoreConfig, TileCacheConfig};
+use tilemachine::custom_script::{params_query_string, CustomScript, ParamValues};
+use tilemachine::encoding::{encode, select_format, OutputFormat};
+use tilemachine::ogcapi;
+use tilemachine::render_pool::RenderPool;
+use tilemachine::script_store::{
+    load_script, register_script, DiskScriptStore, MemoryScriptStore, ScriptStore,
+};
+use tilemachine::source::{init_dataset_pool, open_source};
+use tilemachine::tile_cache::{
+    DiskTileCache, MemoryTileCache, NoTileCache, TileCache, TileCacheKey,
+};
+use tilemachine::tilejson;
+use tilemachine::tms::{TileMatrixSet, TileMatrixSets};
 use tilemachine::utils::{Error, ScriptError};
-use tilemachine::wms;
+use tilemachine::wms::{self, WmsRequest};
+use tilemachine::wmts::{self, GetTileRequest, WmtsRequest};
 
-fn setup_gdal() {
-    env::set_var("VSI_CACHE", "TRUE");
-    env::set_var("GDAL_DISABLE_READDIR_ON_OPEN", "TRUE");
-    env::set_var("AWS_ACCESS_KEY_ID", "minioadmin");
-    env::set_var("AWS_SECRET_ACCESS_KEY", "minioadmin");
-    env::set_var("AWS_S3_ENDPOINT", "localhost:9000");
-    env::set_var("AWS_VIRTUAL_HOSTING", "FALSE");
-    env::set_var("AWS_HTTPS", "FALSE");
-    // TODO: Enable for verbose debugging
-    env::set_var("CPL_DEBUG", "0");
+fn setup_gdal(config: &Config) {
+    for (key, value) in config.gdal_options() {
+        log::debug!("Setting GDAL option {}", key);
+        if let Err(e) = gdal::config::set_config_option(&key, &value) {
+            log::error!("Failed to set GDAL option {}: {:?}", key, e);
+        }
+    }
 }
 
-fn respond_with_error(message: &str, error: &Error) -> HttpResponse {
-    log::error!("{}: {:?}", message, error);
-    let mut extended_message = message.to_string();
-    match error {
-        Error::ScriptError(ScriptError::CompilationError(reason)) => {
-            extended_message += &format!(": {}", reason);
+// Delay clients should wait before retrying when the render pool is saturated
+const RETRY_AFTER_SECS: u64 = 1;
+
+fn create_script_store(config: &ScriptStoreConfig) -> Arc<dyn ScriptStore> {
+    match config {
+        ScriptStoreConfig::Memory => Arc::new(MemoryScriptStore::default()),
+        ScriptStoreConfig::Disk { dir } => {
+            log::info!("storing scripts in {}", dir.display());
+            Arc::new(DiskScriptStore::new(dir.clone()))
         }
-        Error::ScriptError(ScriptError::InvalidReturnType) => {
-            extended_message += ": invalid return type";
+    }
+}
+
+fn create_tile_cache(config: &TileCacheConfig) -> Arc<dyn TileCache> {
+    match config {
+        TileCacheConfig::Memory { size } => {
+            log::info!("caching up to {} tiles in memory", size);
+            Arc::new(MemoryTileCache::new(*size))
         }
-        Error::ScriptError(ScriptError::RuntimeError(e)) => {
-            extended_message += &format!(": {}", e);
+        TileCacheConfig::Disk { dir } => {
+            log::info!("caching tiles in {}", dir.display());
+            Arc::new(DiskTileCache::new(dir.clone()))
         }
-        _ => {}
+        TileCacheConfig::None => Arc::new(NoTileCache),
+    }
+}
+
+// Whether the If-None-Match header of the request matches the given entity tag
+fn etag_matches(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim())
+        .any(|v| v == "*" || v == etag || v.strip_prefix("W/") == Some(etag))
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    // Machine readable error code, e.g. "script_compilation_error"
+    code: &'static str,
+    message: String,
+    // Position of the error in the script
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+}
+
+// Returns the HTTP status, the error code and the details to report to the client
+fn describe_error(error: &Error) -> (StatusCode, &'static str, Option<String>) {
+    match error {
+        Error::SerdeError(e) => (
+            StatusCode::BAD_REQUEST,
+            "invalid_script",
+            Some(e.to_string()),
+        ),
+        Error::ScriptError(ScriptError::CompilationError(e)) => (
+            StatusCode::BAD_REQUEST,
+            "script_compilation_error",
+            Some(e.to_string()),
+        ),
+        Error::ScriptError(ScriptError::RuntimeError(e)) => (
+            StatusCode::BAD_REQUEST,
+            "script_runtime_error",
+            Some(e.to_string()),
+        ),
+        Error::ScriptError(ScriptError::NotEnoughinputs) => (
+            StatusCode::BAD_REQUEST,
+            "not_enough_inputs",
+            Some("the script needs at least one input".to_string()),
+        ),
+        Error::ScriptError(ScriptError::InvalidReturnType) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_return_type",
+            Some("invalid return type".to_string()),
+        ),
+        Error::ScriptError(ScriptError::InvalidPixelValue {
+            row,
+            column,
+            expected,
+            value,
+        }) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "invalid_pixel_value",
+            Some(format!(
+                "expected {} for the pixel at row {}, column {}, got {}",
+                expected, row, column, value
+            )),
+        ),
+        Error::InvalidPath(reason) => (
+            StatusCode::BAD_REQUEST,
+            "invalid_path",
+            Some(reason.to_string()),
+        ),
+        Error::InvalidParameter(reason) => (
+            StatusCode::BAD_REQUEST,
+            "invalid_parameter",
+            Some(reason.to_string()),
+        ),
+        Error::DatasetNotFound(reason) => (
+            StatusCode::NOT_FOUND,
+            "dataset_not_found",
+            Some(reason.to_string()),
+        ),
+        Error::ScriptNotFound(id) => (
+            StatusCode::NOT_FOUND,
+            "script_not_found",
+            Some(format!("no script registered with ID {}", id)),
+        ),
+        Error::UpstreamError(reason) => (
+            StatusCode::BAD_GATEWAY,
+            "upstream_error",
+            Some(reason.to_string()),
+        ),
+        Error::UpstreamTimeout(reason) => (
+            StatusCode::GATEWAY_TIMEOUT,
+            "upstream_timeout",
+            Some(reason.to_string()),
+        ),
+        Error::RenderPoolSaturated => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "server_busy",
+            Some("server busy".to_string()),
+        ),
+        Error::DatasetPoolExhausted => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "server_busy",
+            Some("too many open datasets".to_string()),
+        ),
+        // Internal errors, details are only logged
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "internal_error", None),
     }
-    HttpResponse::InternalServerError().body(extended_message.to_string())
+}
+
+fn respond_with_error(message: &str, error: &Error) -> HttpResponse {
+    let (status, code, details) = describe_error(error);
+    if status.is_server_error() {
+        log::error!("{}: {:?}", message, error);
+    } else {
+        log::info!("{}: {:?}", message, error);
+    }
+    let (line, column) = match error {
+        Error::ScriptError(ScriptError::CompilationError(e))
+        | Error::ScriptError(ScriptError::RuntimeError(e)) => (e.line, e.column),
+        _ => (None, None),
+    };
+    let body = ErrorBody {
+        code,
+        message: match details {
+            Some(details) => format!("{}: {}", message, details),
+            None => message.to_string(),
+        },
+        line,
+        column,
+    };
+    let mut response = HttpResponse::build(status);
+    if let Error::RenderPoolSaturated | Error::DatasetPoolExhausted = error {
+        response.insert_header((header::RETRY_AFTER, RETRY_AFTER_SECS.to_string()));
+    }
+    response.json(body)
 }
 
 #[get("/wms/{custom_script:.+}/service")]
 async fn get_wms(
+    req: HttpRequest,
+    pool: web::Data<RenderPool>,
+    scripts: web::Data<dyn ScriptStore>,
     path: web::Path<String>,
     query: web::Query<HashMap<String, String>>,
 ) -> HttpResponse {
-    let custom_script = match CustomScript::new_from_str(&path.into_inner()) {
+    let custom_script = match load_script(&**scripts, &path.into_inner()) {
         Ok(script) => script,
-        Err(e) => return respond_with_error("Failed to parse custom script", &e),
+        Err(e) => return respond_with_error("Failed to load custom script", &e),
+    };
+    let request = match wms::parse_request(&query) {
+        Ok(request) => request,
+        Err(e) => return respond_with_error("Invalid WMS request", &e),
     };
-    // TODO: Parse query params
-    println!("query_params: {:?}", query.get("SERVICE"));
-    match wms::capabilities(&custom_script, &open_source) {
+    let params = match custom_script.resolve_params(&query) {
+        Ok(params) => params,
+        Err(e) => return respond_with_error("Invalid script parameters", &e),
+    };
+    match request {
+        WmsRequest::GetCapabilities => {
+            let service_url = {
+                let conn = req.connection_info();
+                format!("{}://{}{}", conn.scheme(), conn.host(), req.path())
+            };
+            let params_query = params_query_string(&query);
+            let result = pool
+                .run(move || {
+                    wms::capabilities(&custom_script, &service_url, &params_query, &open_source)
+                })
+                .await;
+            match result {
+                Ok(xml) => HttpResponse::Ok()
+                    .content_type(ContentType::xml())
+                    .body(xml),
+                Err(e) => respond_with_error("Failed to generate capabilities", &e),
+            }
+        }
+        WmsRequest::GetMap(get_map) => {
+            let format = get_map.format;
+            if !format.supports(custom_script.has_float_output()) {
+                let e = Error::InvalidParameter(format!(
+                    "Unsupported FORMAT for this script: {}",
+                    format.mime_type()
+                ));
+                return respond_with_error("Invalid WMS request", &e);
+            }
+            let result = pool
+                .run(move || {
+                    let image_data =
+                        custom_script.execute_on_window(&get_map.window, &params, &open_source)?;
+                    encode(&image_data, &get_map.window, format)
+                })
+                .await;
+            match result {
+                Ok(image) => HttpResponse::Ok()
+                    .content_type(format.mime_type())
+                    .body(image),
+                Err(e) => respond_with_error("Failed to render map", &e),
+            }
+        }
+    }
+}
+
+// A tile of a tile matrix set to serve, see serve_tile
+struct TileRequest<'a> {
+    tms: &'a TileMatrixSet,
+    // Id of the tile matrix
+    z: &'a str,
+    x: u64,
+    y: u64,
+    // See parse_tile_x
+    tile_size: usize,
+    format: OutputFormat,
+    // Values of the script parameters, see CustomScript::resolve_params
+    params: ParamValues,
+}
+
+// Renders a tile, or gets it from the cache. `vary_accept` is set when the format was taken from
+// the Accept header
+async fn serve_tile(
+    req: &HttpRequest,
+    pool: web::Data<RenderPool>,
+    cache: web::Data<dyn TileCache>,
+    custom_script: CustomScript,
+    tile: TileRequest<'_>,
+    vary_accept: bool,
+) -> HttpResponse {
+    let TileRequest {
+        tms,
+        z,
+        x,
+        y,
+        tile_size,
+        format,
+        params,
+    } = tile;
+    let z = match tms.matrix_index(z) {
+        Some(index) => index,
+        None => {
+            let e = Error::InvalidPath(format!("unknown tile matrix {} in {}", z, tms.id));
+            return respond_with_error("Invalid tile coordinates", &e);
+        }
+    };
+    let scale = tile_size / TILE_SIZE as usize;
+    let window = match tms.tile_window(&tms.tile_matrices[z], x, y, scale) {
+        Ok(window) => window,
+        Err(e) => return respond_with_error("Invalid tile coordinates", &e),
+    };
+    let key = match custom_script.hash_with_params(&params) {
+        Ok(script_hash) => TileCacheKey {
+            script_hash,
+            tms: tms.id.clone(),
+            z: z as u64,
+            x,
+            y,
+            tile_size,
+            format: format.cache_name(),
+        },
+        Err(e) => return respond_with_error("Failed to hash custom script", &e),
+    };
+    // The tag only identifies the script, its parameters and the tile, not the data of the
+    // inputs: a raster replaced in place keeps being served from the client caches (and the tile
+    // cache) with the old content. Inputs are expected to be immutable, new data should get a new
+    // path, which changes the script hash
+    let etag = key.etag();
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(format.mime_type())
+        .insert_header((header::ETAG, etag.clone()));
+    if vary_accept {
+        response.insert_header((header::VARY, "Accept"));
+    }
+    if etag_matches(req, &etag) {
+        return HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .finish();
+    }
+    if let Some(tile) = cache.get(&key) {
+        return response.body(tile);
+    }
+
+    let cache = cache.into_inner();
+    let result = pool
+        .run(move || {
+            let image_data = custom_script.execute_on_window(&window, &params, &open_source)?;
+            let tile = encode(&image_data, &window, format)?;
+            cache.put(&key, &tile);
+            Ok(tile)
+        })
+        .await;
+    match result {
+        Ok(tile) => response.body(tile),
+        Err(e) => respond_with_error("Failed to extract tile", &e),
+    }
+}
+
+// Path of the XYZ-like tile routes
+#[derive(Deserialize)]
+struct TilePath {
+    tms: String,
+    custom_script: String,
+    z: String,
+    y: u64,
+    x: String,
+}
+
+// tms is the id of a tile matrix set (`xyz` for WebMercatorQuad) and z the id of one of its
+// tile matrices. x can have a scale suffix for high-DPI tiles and an extension selecting the
+// format, e.g. `12@2x.jpg` for a 512x512 JPEG tile (see encoding::select_format)
+async fn tile_response(
+    req: &HttpRequest,
+    pool: web::Data<RenderPool>,
+    cache: web::Data<dyn TileCache>,
+    scripts: web::Data<dyn ScriptStore>,
+    tile_matrix_sets: &TileMatrixSets,
+    path: TilePath,
+    query: &HashMap<String, String>,
+) -> HttpResponse {
+    let tms = match tile_matrix_sets.get(&path.tms) {
+        Some(tms) => tms,
+        None => {
+            let e = Error::InvalidPath(format!("unknown tile matrix set: {}", path.tms));
+            return respond_with_error("Invalid tile coordinates", &e);
+        }
+    };
+    let (x, extension) = match path.x.split_once('.') {
+        Some((x, extension)) => (x, Some(extension)),
+        None => (path.x.as_str(), None),
+    };
+    let (x, tile_size) = match parse_tile_x(x) {
+        Ok(parsed) => parsed,
+        Err(e) => return respond_with_error("Invalid tile coordinates", &e),
+    };
+    let custom_script = match load_script(&**scripts, &path.custom_script) {
+        Ok(script) => script,
+        Err(e) => return respond_with_error("Failed to load custom script", &e),
+    };
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok());
+    let quality = query.get("quality").map(|q| q.as_str());
+    let format = match select_format(extension, accept, quality, custom_script.has_float_output()) {
+        Ok(format) => format,
+        Err(e) => return respond_with_error("Invalid tile format", &e),
+    };
+    let params = match custom_script.resolve_params(query) {
+        Ok(params) => params,
+        Err(e) => return respond_with_error("Invalid script parameters", &e),
+    };
+    let tile = TileRequest {
+        tms,
+        z: &path.z,
+        x,
+        y: path.y,
+        tile_size,
+        format,
+        params,
+    };
+    serve_tile(req, pool, cache, custom_script, tile, extension.is_none()).await
+}
+
+// raster_path can be a fullpath, in which case it needs to be urlencoded (%2F instead of /)
+// See tile_response for the tile coordinates
+#[get("/tile/{tms}/{custom_script:.+}/{z}/{y}/{x}", name = "tile")]
+async fn get_tile(
+    req: HttpRequest,
+    pool: web::Data<RenderPool>,
+    cache: web::Data<dyn TileCache>,
+    scripts: web::Data<dyn ScriptStore>,
+    tile_matrix_sets: web::Data<TileMatrixSets>,
+    path: web::Path<TilePath>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let path = path.into_inner();
+    tile_response(&req, pool, cache, scripts, &tile_matrix_sets, path, &query).await
+}
+
+// Serves a WMTS GetTile request, with the tile matrix set (and the tile matrix) given by id
+async fn serve_wmts_tile(
+    req: &HttpRequest,
+    pool: web::Data<RenderPool>,
+    cache: web::Data<dyn TileCache>,
+    tile_matrix_sets: &TileMatrixSets,
+    custom_script: CustomScript,
+    wmts_tile: GetTileRequest,
+    params: ParamValues,
+) -> HttpResponse {
+    let tms = match tile_matrix_sets.get(&wmts_tile.tile_matrix_set) {
+        Some(tms) => tms,
+        None => {
+            let e = Error::InvalidParameter(format!(
+                "Unknown TILEMATRIXSET: {}",
+                wmts_tile.tile_matrix_set
+            ));
+            return respond_with_error("Invalid WMTS request", &e);
+        }
+    };
+    if !wmts_tile.format.supports(custom_script.has_float_output()) {
+        let e = Error::InvalidParameter(format!(
+            "Unsupported FORMAT for this script: {}",
+            wmts_tile.format.mime_type()
+        ));
+        return respond_with_error("Invalid WMTS request", &e);
+    }
+    let tile = TileRequest {
+        tms,
+        z: &wmts_tile.tile_matrix,
+        x: wmts_tile.col,
+        y: wmts_tile.row,
+        tile_size: TILE_SIZE as usize,
+        format: wmts_tile.format,
+        params,
+    };
+    serve_tile(req, pool, cache, custom_script, tile, false).await
+}
+
+// The base URL of a service of a script, given the suffix of the request path after it
+fn service_url(req: &HttpRequest, suffix: &str) -> String {
+    let conn = req.connection_info();
+    let path = req.path();
+    format!(
+        "{}://{}{}",
+        conn.scheme(),
+        conn.host(),
+        path.strip_suffix(suffix).unwrap_or(path)
+    )
+}
+
+// The script parameters of the query are kept in the advertised URLs
+async fn wmts_capabilities(
+    pool: web::Data<RenderPool>,
+    tile_matrix_sets: web::Data<TileMatrixSets>,
+    custom_script: CustomScript,
+    service_url: String,
+    query: &HashMap<String, String>,
+) -> HttpResponse {
+    if let Err(e) = custom_script.resolve_params(query) {
+        return respond_with_error("Invalid script parameters", &e);
+    }
+    let params_query = params_query_string(query);
+    let result = pool
+        .run(move || {
+            wmts::capabilities(
+                &custom_script,
+                &tile_matrix_sets,
+                &service_url,
+                &params_query,
+                &open_source,
+            )
+        })
+        .await;
+    match result {
         Ok(xml) => HttpResponse::Ok()
             .content_type(ContentType::xml())
             .body(xml),
@@ -61,64 +529,469 @@ async fn get_wms(
     }
 }
 
-// raster_path can be a fullpath, in which case it needs to be urlencoded (%2F instead of /)
-#[get("/tile/xyz/{custom_script:.+}/{z}/{y}/{x}")]
-async fn get_xyz_tile(path: web::Path<(String, u64, u64, u64)>) -> HttpResponse {
-    let (custom_script, z, y, x) = path.into_inner();
-    let custom_script = match CustomScript::new_from_str(&custom_script) {
+// KVP encoding
+#[get("/wmts/{custom_script:.+}/service")]
+async fn get_wmts(
+    req: HttpRequest,
+    pool: web::Data<RenderPool>,
+    cache: web::Data<dyn TileCache>,
+    scripts: web::Data<dyn ScriptStore>,
+    tile_matrix_sets: web::Data<TileMatrixSets>,
+    path: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let custom_script = match load_script(&**scripts, &path.into_inner()) {
         Ok(script) => script,
-        Err(e) => return respond_with_error("Failed to parse custom script", &e),
+        Err(e) => return respond_with_error("Failed to load custom script", &e),
     };
-    match custom_script.execute_on_tile(&TileCoords { x, y, zoom: z }, &open_source) {
-        Ok(image_data) => HttpResponse::Ok()
-            .content_type(ContentType::png())
-            .body(image_data.to_png()),
-        Err(e) => respond_with_error("Failed to extract tile", &e),
+    let request = match wmts::parse_request(&query) {
+        Ok(request) => request,
+        Err(e) => return respond_with_error("Invalid WMTS request", &e),
+    };
+    match request {
+        WmtsRequest::GetCapabilities => {
+            let service_url = service_url(&req, "/service");
+            wmts_capabilities(pool, tile_matrix_sets, custom_script, service_url, &query).await
+        }
+        WmtsRequest::GetTile(wmts_tile) => {
+            let params = match custom_script.resolve_params(&query) {
+                Ok(params) => params,
+                Err(e) => return respond_with_error("Invalid script parameters", &e),
+            };
+            serve_wmts_tile(
+                &req,
+                pool,
+                cache,
+                &tile_matrix_sets,
+                custom_script,
+                wmts_tile,
+                params,
+            )
+            .await
+        }
+    }
+}
+
+// RESTful encoding
+#[get("/wmts/{custom_script:.+}/1.0.0/WMTSCapabilities.xml")]
+async fn get_wmts_capabilities(
+    req: HttpRequest,
+    pool: web::Data<RenderPool>,
+    scripts: web::Data<dyn ScriptStore>,
+    tile_matrix_sets: web::Data<TileMatrixSets>,
+    path: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let custom_script = match load_script(&**scripts, &path.into_inner()) {
+        Ok(script) => script,
+        Err(e) => return respond_with_error("Failed to load custom script", &e),
+    };
+    let service_url = service_url(&req, "/1.0.0/WMTSCapabilities.xml");
+    wmts_capabilities(pool, tile_matrix_sets, custom_script, service_url, &query).await
+}
+
+// RESTful encoding, see the ResourceURL template in wmts_capabilities.xml
+#[get("/wmts/{custom_script:.+}/tile/1.0.0/{layer}/{style}/{tms}/{z}/{y}/{x}")]
+async fn get_wmts_tile(
+    req: HttpRequest,
+    pool: web::Data<RenderPool>,
+    cache: web::Data<dyn TileCache>,
+    scripts: web::Data<dyn ScriptStore>,
+    tile_matrix_sets: web::Data<TileMatrixSets>,
+    path: web::Path<(String, String, String, String, String, String, String)>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let (custom_script, layer, style, tms, z, y, x) = path.into_inner();
+    let custom_script = match load_script(&**scripts, &custom_script) {
+        Ok(script) => script,
+        Err(e) => return respond_with_error("Failed to load custom script", &e),
+    };
+    let wmts_tile = match wmts::parse_rest_get_tile(&layer, &style, &tms, &z, &y, &x) {
+        Ok(wmts_tile) => wmts_tile,
+        Err(e) => return respond_with_error("Invalid WMTS request", &e),
+    };
+    let params = match custom_script.resolve_params(&query) {
+        Ok(params) => params,
+        Err(e) => return respond_with_error("Invalid script parameters", &e),
+    };
+    serve_wmts_tile(
+        &req,
+        pool,
+        cache,
+        &tile_matrix_sets,
+        custom_script,
+        wmts_tile,
+        params,
+    )
+    .await
+}
+
+#[derive(Serialize)]
+struct RegisteredScript {
+    id: String,
+}
+
+// Registers the script in the body, which can then be used in URLs by its ID instead of inline
+#[post("/scripts")]
+async fn post_script(scripts: web::Data<dyn ScriptStore>, body: String) -> HttpResponse {
+    match register_script(&**scripts, &body) {
+        Ok(id) => HttpResponse::Created()
+            .insert_header((header::LOCATION, format!("/scripts/{}", id)))
+            .json(RegisteredScript { id }),
+        Err(e) => respond_with_error("Failed to register custom script", &e),
+    }
+}
+
+#[get("/scripts/{id}")]
+async fn get_script(scripts: web::Data<dyn ScriptStore>, id: web::Path<String>) -> HttpResponse {
+    match load_script(&**scripts, &id.into_inner()).and_then(|s| s.to_canonical_json()) {
+        Ok(json) => HttpResponse::Ok()
+            .content_type(ContentType::json())
+            .body(json),
+        Err(e) => respond_with_error("Failed to load custom script", &e),
     }
 }
 
 #[get("/bounds/{custom_script:.+}")]
-async fn get_bounds(script: web::Path<String>) -> HttpResponse {
-    let custom_script = match CustomScript::new_from_str(&script.into_inner()) {
+async fn get_bounds(
+    pool: web::Data<RenderPool>,
+    scripts: web::Data<dyn ScriptStore>,
+    script: web::Path<String>,
+) -> HttpResponse {
+    let custom_script = match load_script(&**scripts, &script.into_inner()) {
         Ok(script) => script,
-        Err(e) => return respond_with_error("Failed to parse custom script", &e),
+        Err(e) => return respond_with_error("Failed to load custom script", &e),
     };
 
-    match custom_script.get_bounds_as_polygon(&open_source) {
+    match pool
+        .run(move || custom_script.get_bounds_as_polygon(&open_source))
+        .await
+    {
         Ok(bounds) => HttpResponse::Ok().json(bounds),
         Err(e) => respond_with_error("Failed to compute bounds", &e),
     }
 }
 
-async fn default_route(req: HttpRequest) -> HttpResponse {
-    HttpResponse::NotFound().body(format!("Not found: {:?}", req.path()))
+// The template of the XYZ tile URLs of a script, with {z}, {y} and {x} placeholders. This is
+// generated from the get_tile route, so it follows it and the prefix the app is mounted under
+fn xyz_tiles_url(req: &HttpRequest, custom_script: &str, extension: &str) -> Result<String, Error> {
+    let mut url = req
+        .url_for("tile", ["xyz", custom_script, "z", "y", "x"])
+        .map_err(|e| Error::InvalidPath(format!("cannot build the tiles URL: {:?}", e)))?;
+    // url_for would percent-encode the braces of the placeholders, so they are added afterwards
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.pop().pop().pop();
+    }
+    Ok(format!("{}/{{z}}/{{y}}/{{x}}.{}", url, extension))
+}
+
+// The TileJSON of the XYZ tiles of a script. The `format` query parameter is the extension of
+// the tiles, by default the one of encoding::select_format. Script parameters (`param.*`) are
+// kept in the tiles URL
+#[get("/tilejson/{custom_script:.+}")]
+async fn get_tilejson(
+    req: HttpRequest,
+    pool: web::Data<RenderPool>,
+    scripts: web::Data<dyn ScriptStore>,
+    path: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let script_path = path.into_inner();
+    let custom_script = match load_script(&**scripts, &script_path) {
+        Ok(script) => script,
+        Err(e) => return respond_with_error("Failed to load custom script", &e),
+    };
+    let extension = query.get("format").map(|f| f.as_str());
+    let format = match select_format(extension, None, None, custom_script.has_float_output()) {
+        Ok(format) => format,
+        Err(e) => return respond_with_error("Invalid tile format", &e),
+    };
+    if let Err(e) = custom_script.resolve_params(&query) {
+        return respond_with_error("Invalid script parameters", &e);
+    }
+    let mut tiles_url = match xyz_tiles_url(&req, &script_path, format.extension()) {
+        Ok(url) => url,
+        Err(e) => return respond_with_error("Failed to build the tiles URL", &e),
+    };
+    let params_query = params_query_string(&query);
+    if !params_query.is_empty() {
+        tiles_url = format!("{}?{}", tiles_url, params_query);
+    }
+    let result = pool
+        .run(move || {
+            let bbox = custom_script.get_bounds(&open_source)?;
+            let native_resolution = custom_script.native_resolution(&open_source)?;
+            Ok(tilejson::tilejson(tiles_url, &bbox, native_resolution))
+        })
+        .await;
+    match result {
+        Ok(doc) => HttpResponse::Ok().json(doc),
+        Err(e) => respond_with_error("Failed to compute bounds", &e),
+    }
+}
+
+// Responds with a JSON document built from the WGS84 bounds of the script
+async fn json_with_bounds<F>(
+    pool: web::Data<RenderPool>,
+    scripts: web::Data<dyn ScriptStore>,
+    custom_script: &str,
+    build: F,
+) -> HttpResponse
+where
+    F: FnOnce(&BoundingBox) -> Value + Send + 'static,
+{
+    let custom_script = match load_script(&**scripts, custom_script) {
+        Ok(script) => script,
+        Err(e) => return respond_with_error("Failed to load custom script", &e),
+    };
+    match pool
+        .run(move || Ok(build(&custom_script.get_bounds(&open_source)?)))
+        .await
+    {
+        Ok(doc) => HttpResponse::Ok().json(doc),
+        Err(e) => respond_with_error("Failed to compute bounds", &e),
+    }
+}
+
+// OGC API - Tiles, see ogcapi.rs. The single collection is named after wms::LAYER_NAME
+#[get("/ogcapi/{custom_script:.+}/conformance")]
+async fn get_ogcapi_conformance() -> HttpResponse {
+    HttpResponse::Ok().json(ogcapi::conformance())
+}
+
+#[get("/ogcapi/{custom_script:.+}/tileMatrixSets")]
+async fn get_ogcapi_tile_matrix_sets(
+    req: HttpRequest,
+    tile_matrix_sets: web::Data<TileMatrixSets>,
+) -> HttpResponse {
+    let api_url = service_url(&req, "/tileMatrixSets");
+    HttpResponse::Ok().json(ogcapi::tile_matrix_sets(&api_url, &tile_matrix_sets.all()))
+}
+
+#[get("/ogcapi/{custom_script:.+}/tileMatrixSets/{tms}")]
+async fn get_ogcapi_tile_matrix_set(
+    tile_matrix_sets: web::Data<TileMatrixSets>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (_, tms) = path.into_inner();
+    match tile_matrix_sets.get(&tms) {
+        Some(tms) => HttpResponse::Ok().json(tms),
+        None => {
+            let e = Error::InvalidPath(format!("unknown tile matrix set: {}", tms));
+            respond_with_error("Invalid tile matrix set", &e)
+        }
+    }
+}
+
+#[get("/ogcapi/{custom_script:.+}/collections")]
+async fn get_ogcapi_collections(
+    req: HttpRequest,
+    pool: web::Data<RenderPool>,
+    scripts: web::Data<dyn ScriptStore>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let api_url = service_url(&req, "/collections");
+    json_with_bounds(pool, scripts, &path.into_inner(), move |bbox| {
+        ogcapi::collections(&api_url, bbox)
+    })
+    .await
+}
+
+#[get("/ogcapi/{custom_script:.+}/collections/image")]
+async fn get_ogcapi_collection(
+    req: HttpRequest,
+    pool: web::Data<RenderPool>,
+    scripts: web::Data<dyn ScriptStore>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let api_url = service_url(&req, "/collections/image");
+    json_with_bounds(pool, scripts, &path.into_inner(), move |bbox| {
+        ogcapi::collection(&api_url, bbox)
+    })
+    .await
+}
+
+#[get("/ogcapi/{custom_script:.+}/collections/image/map/tiles")]
+async fn get_ogcapi_tilesets(
+    req: HttpRequest,
+    tile_matrix_sets: web::Data<TileMatrixSets>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let api_url = service_url(&req, "/collections/image/map/tiles");
+    let params_query = params_query_string(&query);
+    HttpResponse::Ok().json(ogcapi::tilesets(
+        &api_url,
+        &params_query,
+        &tile_matrix_sets.all(),
+    ))
+}
+
+#[get("/ogcapi/{custom_script:.+}/collections/image/map/tiles/{tms}")]
+async fn get_ogcapi_tileset(
+    req: HttpRequest,
+    pool: web::Data<RenderPool>,
+    scripts: web::Data<dyn ScriptStore>,
+    tile_matrix_sets: web::Data<TileMatrixSets>,
+    path: web::Path<(String, String)>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let (custom_script, tms) = path.into_inner();
+    let tms = match tile_matrix_sets.get(&tms) {
+        Some(tms) => tms.clone(),
+        None => {
+            let e = Error::InvalidPath(format!("unknown tile matrix set: {}", tms));
+            return respond_with_error("Invalid tile matrix set", &e);
+        }
+    };
+    let custom_script = match load_script(&**scripts, &custom_script) {
+        Ok(script) => script,
+        Err(e) => return respond_with_error("Failed to load custom script", &e),
+    };
+    let suffix = format!("/collections/image/map/tiles/{}", tms.id);
+    let api_url = service_url(&req, &suffix);
+    let params_query = params_query_string(&query);
+    // Like the TileJSON max zoom, the limits stop at the native resolution of the inputs
+    let result = pool
+        .run(move || {
+            let bbox = custom_script.get_bounds(&open_source)?;
+            let native_resolution = custom_script.native_resolution(&open_source)?;
+            let limits = tms.limits(&bbox, native_resolution)?;
+            Ok(ogcapi::tileset(
+                &api_url,
+                &params_query,
+                &tms,
+                &bbox,
+                &limits,
+            ))
+        })
+        .await;
+    match result {
+        Ok(doc) => HttpResponse::Ok().json(doc),
+        Err(e) => respond_with_error("Failed to compute bounds", &e),
+    }
 }
 
-// https://docs.rs/tokio/0.2.20/tokio/index.html#cpu-bound-tasks-and-blocking-code
+#[get("/ogcapi/{custom_script:.+}/collections/image/map/tiles/{tms}/{z}/{y}/{x}")]
+async fn get_ogcapi_tile(
+    req: HttpRequest,
+    pool: web::Data<RenderPool>,
+    cache: web::Data<dyn TileCache>,
+    scripts: web::Data<dyn ScriptStore>,
+    tile_matrix_sets: web::Data<TileMatrixSets>,
+    path: web::Path<TilePath>,
+    query: web::Query<HashMap<String, String>>,
+) -> HttpResponse {
+    let path = path.into_inner();
+    tile_response(&req, pool, cache, scripts, &tile_matrix_sets, path, &query).await
+}
+
+// The script must be urlencoded here, so the landing page doesn't match the other routes
+#[get("/ogcapi/{custom_script}")]
+async fn get_ogcapi_landing_page(req: HttpRequest) -> HttpResponse {
+    let api_url = service_url(&req, "");
+    HttpResponse::Ok().json(ogcapi::landing_page(&api_url))
+}
+
+async fn default_route(req: HttpRequest) -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorBody {
+        code: "not_found",
+        message: format!("Not found: {:?}", req.path()),
+        line: None,
+        column: None,
+    })
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    simple_logger::init_with_level(log::Level::Info).unwrap();
-    setup_gdal();
+    let config = match Config::load(Cli::parse()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let log_level = match log::Level::from_str(&config.server.log_level) {
+        Ok(level) => level,
+        Err(_) => {
+            eprintln!("Invalid log level: {}", config.server.log_level);
+            std::process::exit(1);
+        }
+    };
+    simple_logger::init_with_level(log_level).unwrap();
+    setup_gdal(&config);
+
+    let num_cpus = std::thread::available_parallelism().unwrap().get();
+    let num_workers = config.server.workers.unwrap_or(num_cpus);
+    log::info!("using {} workers", num_workers);
+
+    // Blocking work (GDAL I/O and script execution) runs on a dedicated pool so it doesn't
+    // stall the actix workers. Since GDAL network calls are blocking, we benefit from having
+    // more render threads than CPUs
+    // https://docs.rs/tokio/0.2.20/tokio/index.html#cpu-bound-tasks-and-blocking-code
+    let render_threads = config.render.threads.unwrap_or(4 * num_cpus);
+    let render_queue_size = config.render.queue_size.unwrap_or(16 * render_threads);
+    log::info!(
+        "using {} render threads with a queue of {}",
+        render_threads,
+        render_queue_size
+    );
+    let pool = web::Data::new(RenderPool::new(render_threads, render_queue_size));
+    init_dataset_pool(
+        config.dataset_pool.max_open,
+        config.dataset_pool.max_idle,
+        Duration::from_secs(config.dataset_pool.idle_timeout_secs),
+    );
+    let cache: web::Data<dyn TileCache> = web::Data::from(create_tile_cache(&config.tile_cache));
+    let scripts: web::Data<dyn ScriptStore> =
+        web::Data::from(create_script_store(&config.script_store));
 
-    // actix defaults to `available_parallelism` but since GDAL network calls are blocking
-    // we benefit from having more threads than CPUs
-    // let num_threads = 4 * std::thread::available_parallelism().unwrap().get();
-    let num_threads = std::thread::available_parallelism().unwrap().get();
-    println!("using {} threads", num_threads);
+    let mut custom_tile_matrix_sets = Vec::new();
+    for path in &config.tile_matrix_sets {
+        match TileMatrixSet::from_file(path) {
+            Ok(tms) => {
+                log::info!("loaded tile matrix set {}", tms.id);
+                custom_tile_matrix_sets.push(tms);
+            }
+            Err(e) => {
+                log::error!("Invalid tile matrix set: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let tile_matrix_sets = web::Data::new(TileMatrixSets::new(custom_tile_matrix_sets));
 
-    HttpServer::new(|| {
+    let static_dir = config.server.static_dir.clone();
+    HttpServer::new(move || {
         App::new()
+            .app_data(pool.clone())
+            .app_data(cache.clone())
+            .app_data(scripts.clone())
+            .app_data(tile_matrix_sets.clone())
             .wrap(middleware::Compress::default())
             .service(get_wms)
-            .service(get_xyz_tile)
+            .service(get_wmts)
+            .service(get_wmts_capabilities)
+            .service(get_wmts_tile)
+            .service(get_tile)
+            .service(post_script)
+            .service(get_script)
             .service(get_bounds)
-            .service(fs::Files::new("/", "./web").index_file("index.html"))
+            .service(get_tilejson)
+            .service(get_ogcapi_conformance)
+            .service(get_ogcapi_tile_matrix_sets)
+            .service(get_ogcapi_tile_matrix_set)
+            .service(get_ogcapi_collections)
+            .service(get_ogcapi_collection)
+            .service(get_ogcapi_tilesets)
+            .service(get_ogcapi_tileset)
+            .service(get_ogcapi_tile)
+            .service(get_ogcapi_landing_page)
+            .service(fs::Files::new("/", &static_dir).index_file("index.html"))
             .default_service(web::route().to(default_route))
             .wrap(middleware::Logger::default())
     })
-    .workers(num_threads)
-    .bind(("0.0.0.0", 8080))?
+    .workers(num_workers)
+    .bind((config.server.bind.as_str(), config.server.port))?
     .run()
     .await
 }
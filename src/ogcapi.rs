@@ -0,0 +1,242 @@
+//! Documents of the OGC API - Tiles service of a script: https://docs.ogc.org/is/20-057/20-057.html
+//! The script is published as a single collection with map tilesets in every tile matrix set.
+//! `api_url` is the root of the API, which all the links are relative to. `params_query` holds
+//! the script parameters of the request (see custom_script::params_query_string), which are
+//! kept in the links to the tilesets and tiles
+use crate::bbox::BoundingBox;
+use crate::tms::{TileMatrixLimits, TileMatrixSet};
+use crate::wms::LAYER_NAME;
+use serde_json::{json, Value};
+
+const CONFORMANCE: [&str; 7] = [
+    "http://www.opengis.net/spec/ogcapi-common-1/1.0/conf/core",
+    "http://www.opengis.net/spec/ogcapi-common-1/1.0/conf/json",
+    "http://www.opengis.net/spec/ogcapi-common-2/1.0/conf/collections",
+    "http://www.opengis.net/spec/ogcapi-tiles-1/1.0/conf/core",
+    "http://www.opengis.net/spec/ogcapi-tiles-1/1.0/conf/tileset",
+    "http://www.opengis.net/spec/ogcapi-tiles-1/1.0/conf/tilesets-list",
+    "http://www.opengis.net/spec/ogcapi-tiles-1/1.0/conf/geodata-tilesets",
+];
+
+const CRS84: &str = "http://www.opengis.net/def/crs/OGC/1.3/CRS84";
+
+fn link(rel: &str, href: String, title: &str) -> Value {
+    json!({"rel": rel, "type": "application/json", "title": title, "href": href})
+}
+
+fn collection_url(api_url: &str) -> String {
+    format!("{}/collections/{}", api_url, LAYER_NAME)
+}
+
+fn tilesets_url(api_url: &str) -> String {
+    format!("{}/map/tiles", collection_url(api_url))
+}
+
+fn with_query(url: String, params_query: &str) -> String {
+    if params_query.is_empty() {
+        url
+    } else {
+        format!("{}?{}", url, params_query)
+    }
+}
+
+pub fn landing_page(api_url: &str) -> Value {
+    json!({
+        "title": "tilemachine",
+        "links": [
+            link("self", api_url.to_string(), "This document"),
+            link("conformance", format!("{}/conformance", api_url), "Conformance classes"),
+            link("data", format!("{}/collections", api_url), "Collections"),
+            link(
+                "http://www.opengis.net/def/rel/ogc/1.0/tiling-schemes",
+                format!("{}/tileMatrixSets", api_url),
+                "Tile matrix sets",
+            ),
+        ],
+    })
+}
+
+pub fn conformance() -> Value {
+    json!({ "conformsTo": CONFORMANCE })
+}
+
+pub fn tile_matrix_sets(api_url: &str, tile_matrix_sets: &[&TileMatrixSet]) -> Value {
+    let sets: Vec<Value> = tile_matrix_sets
+        .iter()
+        .map(|tms| {
+            json!({
+                "id": tms.id,
+                "title": tms.title,
+                "uri": tms.uri,
+                "links": [link(
+                    "self",
+                    format!("{}/tileMatrixSets/{}", api_url, tms.id),
+                    &tms.id,
+                )],
+            })
+        })
+        .collect();
+    json!({ "tileMatrixSets": sets })
+}
+
+/// `bbox` is the WGS84 bounds of the script
+pub fn collection(api_url: &str, bbox: &BoundingBox) -> Value {
+    json!({
+        "id": LAYER_NAME,
+        "title": LAYER_NAME,
+        "dataType": "map",
+        "extent": {
+            "spatial": {"bbox": [bbox.to_array()], "crs": CRS84},
+        },
+        "links": [
+            link("self", collection_url(api_url), "This collection"),
+            link(
+                "http://www.opengis.net/def/rel/ogc/1.0/tilesets-map",
+                tilesets_url(api_url),
+                "Map tilesets",
+            ),
+        ],
+    })
+}
+
+pub fn collections(api_url: &str, bbox: &BoundingBox) -> Value {
+    json!({
+        "links": [link("self", format!("{}/collections", api_url), "Collections")],
+        "collections": [collection(api_url, bbox)],
+    })
+}
+
+// The summary of a tileset, as listed in the tilesets
+fn tileset_summary(api_url: &str, params_query: &str, tms: &TileMatrixSet) -> Value {
+    json!({
+        "title": format!("{} tiles in {}", LAYER_NAME, tms.id),
+        "dataType": "map",
+        "crs": tms.crs,
+        "tileMatrixSetURI": tms.uri,
+        "links": [
+            link(
+                "self",
+                with_query(format!("{}/{}", tilesets_url(api_url), tms.id), params_query),
+                "This tileset",
+            ),
+            link(
+                "http://www.opengis.net/def/rel/ogc/1.0/tiling-scheme",
+                format!("{}/tileMatrixSets/{}", api_url, tms.id),
+                "Tile matrix set definition",
+            ),
+        ],
+    })
+}
+
+pub fn tilesets(api_url: &str, params_query: &str, tile_matrix_sets: &[&TileMatrixSet]) -> Value {
+    let tilesets: Vec<Value> = tile_matrix_sets
+        .iter()
+        .map(|tms| tileset_summary(api_url, params_query, tms))
+        .collect();
+    json!({
+        "links": [link(
+            "self",
+            with_query(tilesets_url(api_url), params_query),
+            "Map tilesets",
+        )],
+        "tilesets": tilesets,
+    })
+}
+
+/// The tileset of the script in the given tile matrix set, with the template of its tile URLs.
+/// The format of the tiles is negotiated with the Accept header (see encoding::select_format).
+/// `limits` are the tiles with data, see TileMatrixSet::limits
+pub fn tileset(
+    api_url: &str,
+    params_query: &str,
+    tms: &TileMatrixSet,
+    bbox: &BoundingBox,
+    limits: &[TileMatrixLimits],
+) -> Value {
+    let mut tileset = tileset_summary(api_url, params_query, tms);
+    tileset["boundingBox"] = json!({
+        "lowerLeft": [bbox.xmin, bbox.ymin],
+        "upperRight": [bbox.xmax, bbox.ymax],
+        "crs": CRS84,
+    });
+    tileset["tileMatrixSetLimits"] = json!(limits);
+    if let Some(links) = tileset["links"].as_array_mut() {
+        links.push(json!({
+            "rel": "item",
+            "title": "Tiles",
+            "templated": true,
+            "href": with_query(
+                format!(
+                    "{}/{}/{{tileMatrix}}/{{tileRow}}/{{tileCol}}",
+                    tilesets_url(api_url),
+                    tms.id
+                ),
+                params_query,
+            ),
+        }));
+    }
+    tileset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tileset() {
+        let tms = TileMatrixSet::world_crs84_quad();
+        let bbox = BoundingBox {
+            xmin: 174.0,
+            ymin: -41.0,
+            xmax: 175.0,
+            ymax: -40.0,
+        };
+        let limits = [TileMatrixLimits {
+            tile_matrix: "0".to_string(),
+            min_tile_row: 0,
+            max_tile_row: 0,
+            min_tile_col: 1,
+            max_tile_col: 1,
+        }];
+        let doc = tileset("http://localhost/ogcapi/script", "", &tms, &bbox, &limits);
+        assert_eq!(doc["crs"], tms.crs.as_str());
+        assert_eq!(
+            doc["tileMatrixSetLimits"],
+            json!([{
+                "tileMatrix": "0",
+                "minTileRow": 0,
+                "maxTileRow": 0,
+                "minTileCol": 1,
+                "maxTileCol": 1,
+            }])
+        );
+        assert_eq!(doc["boundingBox"]["upperRight"], json!([175.0, -40.0]));
+        assert_eq!(
+            doc["links"][2]["href"],
+            "http://localhost/ogcapi/script/collections/image/map/tiles/WorldCRS84Quad/{tileMatrix}/{tileRow}/{tileCol}"
+        );
+        let doc = tilesets("http://localhost/ogcapi/script", "", &[&tms]);
+        assert_eq!(
+            doc["tilesets"][0]["links"][1]["href"],
+            "http://localhost/ogcapi/script/tileMatrixSets/WorldCRS84Quad"
+        );
+
+        // Script parameters are kept in the links to the tiles
+        let doc = tileset(
+            "http://localhost/ogcapi/script",
+            "param.a=1",
+            &tms,
+            &bbox,
+            &limits,
+        );
+        assert_eq!(
+            doc["links"][2]["href"],
+            "http://localhost/ogcapi/script/collections/image/map/tiles/WorldCRS84Quad/{tileMatrix}/{tileRow}/{tileCol}?param.a=1"
+        );
+        let doc = tilesets("http://localhost/ogcapi/script", "param.a=1", &[&tms]);
+        assert_eq!(
+            doc["tilesets"][0]["links"][0]["href"],
+            "http://localhost/ogcapi/script/collections/image/map/tiles/WorldCRS84Quad?param.a=1"
+        );
+    }
+}
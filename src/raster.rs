@@ -3,15 +3,19 @@ use crate::utils::Result;
 use gdal::{spatial_ref::CoordTransform, spatial_ref::SpatialRef, Dataset};
 use gdal_sys::OSRAxisMappingStrategy;
 
-fn raster_local_bbox(ds: &Dataset) -> Result<BoundingBox> {
+/// The bounding box of the raster in its own CRS
+pub fn raster_local_bbox(ds: &Dataset) -> Result<BoundingBox> {
     let geot = ds.geo_transform()?;
     let (width, height) = ds.raster_size();
+    let (x0, x1) = (geot[0], geot[0] + width as f64 * geot[1]);
+    // For north-up rasters, geot[5] is negative and the origin is the top-left corner
+    let (y0, y1) = (geot[3], geot[3] + height as f64 * geot[5]);
 
     Ok(BoundingBox {
-        xmin: geot[0],
-        ymin: geot[3],
-        xmax: geot[0] + width as f64 * geot[1],
-        ymax: geot[3] + height as f64 * geot[5],
+        xmin: x0.min(x1),
+        ymin: y0.min(y1),
+        xmax: x0.max(x1),
+        ymax: y0.max(y1),
     })
 }
 
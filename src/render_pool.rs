@@ -0,0 +1,63 @@
+//! A fixed-size pool of threads to run blocking work (GDAL I/O, V8 execution) off the async
+//! executor. The queue of pending jobs is bounded, so that overload is reported to clients
+//! instead of piling up requests
+use crate::utils::{Error, Result};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct RenderPool {
+    sender: SyncSender<Job>,
+}
+
+impl RenderPool {
+    /// Spawn `num_threads` threads, accepting up to `queue_size` jobs waiting for a free thread
+    pub fn new(num_threads: usize, queue_size: usize) -> RenderPool {
+        let (sender, receiver) = sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..num_threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("render-{}", i))
+                .spawn(move || loop {
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        // The pool has been dropped
+                        Err(_) => break,
+                    };
+                    // Keep the thread alive if the job panics. The caller gets notified through
+                    // the dropped result channel
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                })
+                .expect("Failed to spawn render thread");
+        }
+        RenderPool { sender }
+    }
+
+    /// Run `f` on one of the pool threads and wait for its result. Returns
+    /// `Error::RenderPoolSaturated` immediately if the queue is full
+    pub async fn run<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce() -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (result_sender, result_receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // The receiver is gone if the request was cancelled, nothing to do then
+            let _ = result_sender.send(f());
+        });
+        // The only possible error is a full queue, the channel can't be disconnected since
+        // render threads never exit
+        if self.sender.try_send(job).is_err() {
+            return Err(Error::RenderPoolSaturated);
+        }
+        match result_receiver.await {
+            Ok(result) => result,
+            Err(_) => Err(Error::RenderPoolPanic),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Storage of scripts registered with `POST /scripts`, so they can be referred to by ID in URLs
+//! instead of being inlined. The ID of a script is its hash (see CustomScript::hash), so
+//! registering the same script twice gives the same ID
+use crate::custom_script::CustomScript;
+use crate::utils::{Error, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub trait ScriptStore: Send + Sync {
+    /// The canonical JSON of the script with the given ID, if there is one
+    fn get(&self, id: &str) -> Result<Option<String>>;
+    fn put(&self, id: &str, json: &str) -> Result<()>;
+}
+
+/// A store that is lost when the server restarts
+#[derive(Default)]
+pub struct MemoryScriptStore {
+    scripts: Mutex<HashMap<String, String>>,
+}
+
+impl ScriptStore for MemoryScriptStore {
+    fn get(&self, id: &str) -> Result<Option<String>> {
+        Ok(self.scripts.lock().unwrap().get(id).cloned())
+    }
+
+    fn put(&self, id: &str, json: &str) -> Result<()> {
+        self.scripts
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), json.to_string());
+        Ok(())
+    }
+}
+
+/// A store keeping each script in a `{id}.json` file of a directory
+pub struct DiskScriptStore {
+    dir: PathBuf,
+}
+
+impl DiskScriptStore {
+    pub fn new(dir: PathBuf) -> DiskScriptStore {
+        DiskScriptStore { dir }
+    }
+}
+
+impl ScriptStore for DiskScriptStore {
+    fn get(&self, id: &str) -> Result<Option<String>> {
+        let path = self.dir.join(format!("{}.json", id));
+        match fs::read_to_string(&path) {
+            Ok(json) => Ok(Some(json)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::StorageError(format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    fn put(&self, id: &str, json: &str) -> Result<()> {
+        let path = self.dir.join(format!("{}.json", id));
+        // As for DiskTileCache, write to a temporary file and rename so concurrent readers never
+        // see a partial script
+        let tmp_path = path.with_extension(format!("json.tmp{:?}", std::thread::current().id()));
+        fs::create_dir_all(&self.dir)
+            .and_then(|_| fs::write(&tmp_path, json))
+            .and_then(|_| fs::rename(&tmp_path, &path))
+            .map_err(|e| Error::StorageError(format!("{}: {}", path.display(), e)))
+    }
+}
+
+// Whether `id` looks like a script ID, i.e. a hex-encoded SHA-256. This also makes sure it can
+// safely be used as a file name
+fn is_script_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Validates and stores a script given as JSON, and returns its ID
+pub fn register_script(store: &dyn ScriptStore, json: &str) -> Result<String> {
+    let script = CustomScript::new_from_str(json)?;
+    let id = script.hash()?;
+    store.put(&id, &script.to_canonical_json()?)?;
+    Ok(id)
+}
+
+/// Loads the script given in a URL, either inline as JSON or as the ID of a registered script
+pub fn load_script(store: &dyn ScriptStore, script_or_id: &str) -> Result<CustomScript> {
+    if script_or_id.trim_start().starts_with('{') {
+        return CustomScript::new_from_str(script_or_id);
+    }
+    if !is_script_id(script_or_id) {
+        return Err(Error::InvalidPath(format!(
+            "expected a JSON script or a script ID, got {}",
+            script_or_id
+        )));
+    }
+    match store.get(script_or_id)? {
+        Some(json) => CustomScript::new_from_str(&json),
+        None => Err(Error::ScriptNotFound(script_or_id.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"{
+        "inputs": {"rgb": "file:example_data/new_zealand_1_rgb.tif"},
+        "script": "return rgb"
+    }"#;
+
+    #[test]
+    fn test_register_script() {
+        let store = MemoryScriptStore::default();
+        let id = register_script(&store, SCRIPT).unwrap();
+        assert!(is_script_id(&id));
+        // Formatting doesn't change the ID
+        let compact: serde_json::Value = serde_json::from_str(SCRIPT).unwrap();
+        assert_eq!(register_script(&store, &compact.to_string()).unwrap(), id);
+
+        let script = load_script(&store, &id).unwrap();
+        assert_eq!(script.hash().unwrap(), id);
+        assert!(load_script(&store, SCRIPT).is_ok());
+        assert!(matches!(
+            load_script(&store, &"0".repeat(64)),
+            Err(Error::ScriptNotFound(_))
+        ));
+        assert!(matches!(
+            load_script(&store, "../secret"),
+            Err(Error::InvalidPath(_))
+        ));
+        assert!(register_script(&store, "{}").is_err());
+    }
+
+    #[test]
+    fn test_disk_store() {
+        let dir = std::env::temp_dir().join(format!("tilemachine_scripts_{}", std::process::id()));
+        let store = DiskScriptStore::new(dir.clone());
+        assert_eq!(store.get("abcd").unwrap(), None);
+        store.put("abcd", "{}").unwrap();
+        assert_eq!(store.get("abcd").unwrap(), Some("{}".to_string()));
+        assert!(dir.join("abcd.json").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,37 +1,76 @@
 /// A source is an abstraction over a raster datasource. It can be many things:
 /// - A gdal Dataset opened from a raster file (local or from blobstore with GDAL VSI infrastructure)
-/// - An upstream WMS server
-/// - An upstream XYZ server
+/// - An upstream WMS server (`wms:https://example.com/wms?LAYERS=basemap`)
+/// - An upstream XYZ server (`xyz:https://example.com/{z}/{x}/{y}.png#maxzoom=15`)
+mod dataset_pool;
 mod gdal_source;
+mod http;
+mod wms_source;
+mod xyz_source;
 use crate::bbox::BoundingBox;
+use crate::ds_utils::ReprojectOptions;
 use crate::utils::{Error, Result};
+use dataset_pool::dataset_pool;
+pub use dataset_pool::init_dataset_pool;
 use gdal::Dataset;
 use gdal_source::GdalSource;
+use wms_source::WmsSource;
+use xyz_source::XyzSource;
 
 pub trait Source {
     fn num_bands(&self) -> usize;
-    fn reproject_to(&self, target_ds: &Dataset) -> Result<()>;
+    /// Warp the source into `target_ds`, which has one band per band selected in `options`.
+    /// Target pixels with no source data must be left untouched
+    fn reproject_to(&self, target_ds: &Dataset, options: &ReprojectOptions) -> Result<()>;
+
+    /// The nodata value of each band, if any
+    fn nodata(&self) -> Vec<Option<f64>> {
+        vec![None; self.num_bands()]
+    }
+
+    /// The (0-based) index of the band holding the pixels opacity, if any. Pixels with a zero
+    /// alpha are considered invalid
+    fn alpha_band(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether the source has a mask shared by all its bands (e.g. the internal or `.msk` mask
+    /// of a JPEG-compressed COG). Pixels with a zero mask are considered invalid, the mask is
+    /// warped when `ReprojectOptions::mask` is set
+    fn has_mask(&self) -> bool {
+        false
+    }
 
     fn wgs84_bbox(&self) -> Result<BoundingBox>;
+
+    /// The size of the pixels at full resolution, in EPSG:3857 meters so it can be compared to
+    /// XYZ zoom levels (see xyz::zoom_for_resolution), if known
+    fn native_resolution(&self) -> Option<f64> {
+        None
+    }
 }
 
+/// Open the source designated by `path`. GDAL datasets are taken from the dataset pool and
+/// WMS capabilities are cached
 pub fn open_source(path: &str) -> Result<Box<dyn Source>> {
     match path.split_once(':') {
         Some(("file", filename)) => {
-            let source = GdalSource::from_file(filename)?;
+            let source = dataset_pool().get(path, || GdalSource::from_file(filename))?;
             Ok(Box::new(source))
         }
         Some(("s3", s3_path)) => {
-            let source = GdalSource::from_blobstore(s3_path)?;
+            let source = dataset_pool().get(path, || GdalSource::from_blobstore(s3_path))?;
             Ok(Box::new(source))
         }
-        Some(("wms", wms_path)) => {
-            println!("WMS!! {}", wms_path);
-            Result::Err(Error::InvalidPath(format!("WMS path: {}", wms_path)))
+        Some(("wms", wms_url)) => {
+            let source = WmsSource::from_url_cached(wms_url)?;
+            Ok(Box::new(source))
         }
-        _ => {
-            println!("Invalid path {}", path);
-            Result::Err(Error::InvalidPath(path.to_string()))
+        Some(("xyz", xyz_url)) => {
+            let source = XyzSource::from_url(xyz_url)?;
+            Ok(Box::new(source))
         }
+        // Logged by the callers with the rest of the request errors
+        _ => Result::Err(Error::InvalidPath(path.to_string())),
     }
 }
@@ -0,0 +1,386 @@
+//! A pool of opened GDAL datasets keyed by path, so that consecutive tiles on the same raster
+//! don't have to re-open it (which for remote rasters means re-fetching the headers).
+//!
+//! GDAL datasets can't be used concurrently, so a handle is taken out of the pool while in use
+//! and returned to it when dropped. At most `max_open` handles, idle or in use, are open at any
+//! time: idle handles of other paths are closed to make room, and once all of them are in use
+//! `get` waits for one to be released, failing after `max_wait`. Idle handles are closed after
+//! `idle_timeout`, and at most `max_idle` of them are kept.
+use crate::bbox::BoundingBox;
+use crate::ds_utils::ReprojectOptions;
+use crate::source::gdal_source::GdalSource;
+use crate::source::Source;
+use crate::utils::{Error, Result};
+use gdal::Dataset;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_OPEN: usize = 256;
+const DEFAULT_MAX_IDLE: usize = 256;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+// A render holds the handles of its inputs while opening the next ones, so waiting forever
+// could deadlock renders holding each other's handles
+const MAX_WAIT: Duration = Duration::from_secs(10);
+
+static POOL: OnceLock<DatasetPool> = OnceLock::new();
+
+/// Configure the global pool. This has to be called before the first dataset is opened, later
+/// calls are ignored
+pub fn init_dataset_pool(max_open: usize, max_idle: usize, idle_timeout: Duration) {
+    if POOL
+        .set(DatasetPool::new(max_open, max_idle, idle_timeout))
+        .is_err()
+    {
+        log::warn!("Dataset pool already initialized");
+    }
+}
+
+pub fn dataset_pool() -> &'static DatasetPool {
+    POOL.get_or_init(|| DatasetPool::new(DEFAULT_MAX_OPEN, DEFAULT_MAX_IDLE, DEFAULT_IDLE_TIMEOUT))
+}
+
+struct IdleSource {
+    source: GdalSource,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct PoolState {
+    // Idle handles per path, least recently used first
+    idle: HashMap<String, Vec<IdleSource>>,
+    num_idle: usize,
+    // Number of open handles, idle or in use, including the ones being opened
+    num_open: usize,
+}
+
+impl PoolState {
+    fn evict_expired(&mut self, idle_timeout: Duration) {
+        let mut num_evicted = 0;
+        self.idle.retain(|_, sources| {
+            let len = sources.len();
+            sources.retain(|s| s.since.elapsed() < idle_timeout);
+            num_evicted += len - sources.len();
+            !sources.is_empty()
+        });
+        self.num_idle -= num_evicted;
+        self.num_open -= num_evicted;
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let path = self
+            .idle
+            .iter()
+            .min_by_key(|(_, sources)| sources[0].since)
+            .map(|(path, _)| path.clone());
+        if let Some(path) = path {
+            let sources = self.idle.get_mut(&path).unwrap();
+            sources.remove(0);
+            if sources.is_empty() {
+                self.idle.remove(&path);
+            }
+            self.num_idle -= 1;
+            self.num_open -= 1;
+        }
+    }
+}
+
+pub struct DatasetPool {
+    state: Mutex<PoolState>,
+    // Notified when a handle is released or closed
+    released: Condvar,
+    max_open: usize,
+    max_idle: usize,
+    idle_timeout: Duration,
+    max_wait: Duration,
+}
+
+impl DatasetPool {
+    pub fn new(max_open: usize, max_idle: usize, idle_timeout: Duration) -> DatasetPool {
+        DatasetPool {
+            state: Mutex::new(PoolState::default()),
+            released: Condvar::new(),
+            max_open,
+            max_idle,
+            idle_timeout,
+            max_wait: MAX_WAIT,
+        }
+    }
+
+    /// Take an idle handle on `path` out of the pool, or open a new one with `open_fn`. If
+    /// `max_open` handles are in use, wait for one to be released and return
+    /// `Error::DatasetPoolExhausted` if none is within `max_wait`
+    pub fn get(
+        &'static self,
+        path: &str,
+        open_fn: impl FnOnce() -> Result<GdalSource>,
+    ) -> Result<PooledGdalSource> {
+        let deadline = Instant::now() + self.max_wait;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            state.evict_expired(self.idle_timeout);
+            if let Some(sources) = state.idle.get_mut(path) {
+                // Take the most recently used handle, to let the others expire
+                let idle = sources.pop().unwrap();
+                if sources.is_empty() {
+                    state.idle.remove(path);
+                }
+                state.num_idle -= 1;
+                return Ok(PooledGdalSource::new(self, path, idle.source));
+            }
+            if state.num_open < self.max_open {
+                break;
+            }
+            if state.num_idle > 0 {
+                state.evict_least_recently_used();
+                continue;
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(Error::DatasetPoolExhausted);
+            }
+            state = self.released.wait_timeout(state, timeout).unwrap().0;
+        }
+        // Reserve the handle, opening may be slow (network) so don't hold the lock
+        state.num_open += 1;
+        drop(state);
+        match open_fn() {
+            Ok(source) => Ok(PooledGdalSource::new(self, path, source)),
+            Err(e) => {
+                self.close();
+                Err(e)
+            }
+        }
+    }
+
+    fn release(&self, path: String, source: GdalSource) {
+        let mut state = self.state.lock().unwrap();
+        // Without any get, expired handles would otherwise stay open
+        state.evict_expired(self.idle_timeout);
+        if self.max_idle == 0 {
+            drop(state);
+            drop(source);
+            self.close();
+            return;
+        }
+        if state.num_idle >= self.max_idle {
+            state.evict_least_recently_used();
+        }
+        state.idle.entry(path).or_default().push(IdleSource {
+            source,
+            since: Instant::now(),
+        });
+        state.num_idle += 1;
+        self.released.notify_one();
+    }
+
+    // Account for a handle that was closed (or failed to open) while out of the pool
+    fn close(&self) {
+        self.state.lock().unwrap().num_open -= 1;
+        self.released.notify_one();
+    }
+
+    #[cfg(test)]
+    fn num_open(&self) -> usize {
+        self.state.lock().unwrap().num_open
+    }
+
+    #[cfg(test)]
+    fn num_idle(&self) -> usize {
+        self.state.lock().unwrap().num_idle
+    }
+}
+
+/// A `GdalSource` borrowed from the pool, which is returned to it when dropped
+pub struct PooledGdalSource {
+    pool: &'static DatasetPool,
+    path: String,
+    // Only None while being dropped
+    source: Option<GdalSource>,
+}
+
+impl PooledGdalSource {
+    fn new(pool: &'static DatasetPool, path: &str, source: GdalSource) -> PooledGdalSource {
+        PooledGdalSource {
+            pool,
+            path: path.to_string(),
+            source: Some(source),
+        }
+    }
+
+    fn source(&self) -> &GdalSource {
+        self.source.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledGdalSource {
+    fn drop(&mut self) {
+        if let Some(source) = self.source.take() {
+            self.pool.release(std::mem::take(&mut self.path), source);
+        }
+    }
+}
+
+impl Source for PooledGdalSource {
+    fn num_bands(&self) -> usize {
+        self.source().num_bands()
+    }
+
+    fn reproject_to(&self, target_ds: &Dataset, options: &ReprojectOptions) -> Result<()> {
+        self.source().reproject_to(target_ds, options)
+    }
+
+    fn nodata(&self) -> Vec<Option<f64>> {
+        self.source().nodata()
+    }
+
+    fn alpha_band(&self) -> Option<usize> {
+        self.source().alpha_band()
+    }
+
+    fn has_mask(&self) -> bool {
+        self.source().has_mask()
+    }
+
+    fn wgs84_bbox(&self) -> Result<BoundingBox> {
+        self.source().wgs84_bbox()
+    }
+
+    fn native_resolution(&self) -> Option<f64> {
+        self.source().native_resolution()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gdal::spatial_ref::SpatialRef;
+    use gdal::DriverManager;
+
+    fn raster_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!(
+                "tilemachine_pool_{}_{}.tif",
+                std::process::id(),
+                name
+            ))
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn create_raster(name: &str) -> String {
+        let path = raster_path(name);
+        DriverManager::get_driver_by_name("GTiff")
+            .unwrap()
+            .create(&path, 1, 1, 1)
+            .unwrap();
+        path
+    }
+
+    fn leak_pool(max_open: usize, max_idle: usize, idle_timeout: Duration) -> &'static DatasetPool {
+        Box::leak(Box::new(DatasetPool {
+            max_wait: Duration::from_millis(200),
+            ..DatasetPool::new(max_open, max_idle, idle_timeout)
+        }))
+    }
+
+    #[test]
+    fn test_dataset_pool() {
+        let pool = leak_pool(3, 2, Duration::from_secs(60));
+        let a = create_raster("a");
+        let b = create_raster("b");
+        let c = create_raster("c");
+        let open = |path: &str| pool.get(path, || GdalSource::from_file(path));
+
+        // Concurrent uses of the same path get separate handles
+        let a1 = open(&a).unwrap();
+        let a2 = open(&a).unwrap();
+        assert_eq!(pool.num_open(), 2);
+        drop(a1);
+        drop(a2);
+        assert_eq!(pool.num_idle(), 2);
+
+        // Reuse an idle handle
+        let a1 = open(&a).unwrap();
+        assert_eq!(pool.num_open(), 2);
+        drop(a1);
+
+        // Only max_idle handles are kept, so the least recently used handle of a gets closed
+        drop(open(&b).unwrap());
+        assert_eq!(pool.num_idle(), 2);
+        assert_eq!(pool.num_open(), 2);
+
+        // Idle handles are closed to make room for new ones
+        let c1 = open(&c).unwrap();
+        let c2 = open(&c).unwrap();
+        assert_eq!(pool.num_open(), 3);
+        assert_eq!(pool.num_idle(), 1);
+
+        // All the handles in use, opening another one fails once max_wait is elapsed
+        let b1 = open(&b).unwrap();
+        assert!(matches!(open(&a), Err(Error::DatasetPoolExhausted)));
+        // Unless one is released in the meantime
+        let released = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            drop(c1);
+        });
+        let a1 = open(&a).unwrap();
+        released.join().unwrap();
+        assert_eq!(pool.num_open(), 3);
+        drop((a1, b1, c2));
+        assert_eq!(pool.num_idle(), 2);
+
+        // Failures to open don't leak
+        assert!(pool
+            .get("missing", || Err(Error::InvalidPath("missing".to_string())))
+            .is_err());
+        assert_eq!(pool.num_open(), 2);
+
+        for path in [a, b, c] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_idle_eviction() {
+        let pool = leak_pool(2, 2, Duration::ZERO);
+        let a = create_raster("idle");
+        let b = create_raster("idle_b");
+        drop(pool.get(&a, || GdalSource::from_file(&a)).unwrap());
+        assert_eq!(pool.num_idle(), 1);
+        // Expired handles are evicted on the next access
+        drop(pool.get(&a, || GdalSource::from_file(&a)).unwrap());
+        assert_eq!(pool.num_idle(), 1);
+        // and when another handle is released
+        let b1 = pool.get(&b, || GdalSource::from_file(&b)).unwrap();
+        drop(pool.get(&a, || GdalSource::from_file(&a)).unwrap());
+        drop(b1);
+        assert_eq!(pool.num_idle(), 1);
+        assert_eq!(pool.num_open(), 1);
+        for path in [a, b] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_native_resolution() {
+        let path = raster_path("resolution");
+        {
+            let mut ds = DriverManager::get_driver_by_name("GTiff")
+                .unwrap()
+                .create(&path, 10, 10, 1)
+                .unwrap();
+            ds.set_geo_transform(&[1000.0, 2.0, 0.0, 5000.0, 0.0, -2.0])
+                .unwrap();
+            ds.set_spatial_ref(&SpatialRef::from_epsg(3857).unwrap())
+                .unwrap();
+        }
+        // Goes through the pool, which must forward it to the GdalSource
+        let source = crate::source::open_source(&format!("file:{}", path)).unwrap();
+        let resolution = source.native_resolution().unwrap();
+        assert!((resolution - 2.0).abs() < 1e-6, "{}", resolution);
+        drop(source);
+        std::fs::remove_file(path).unwrap();
+    }
+}
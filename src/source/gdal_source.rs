@@ -1,25 +1,94 @@
 use crate::bbox::BoundingBox;
-use crate::raster::wgs84_bbox;
+use crate::ds_utils::{reproject, ReprojectOptions};
+use crate::raster::{raster_local_bbox, raster_projected_bbox, wgs84_bbox};
 use crate::source::Source;
-use crate::utils::Error::GdalError;
+use crate::utils::Error;
 use crate::utils::Result;
-use gdal::Dataset;
+use gdal::spatial_ref::CoordTransform;
+use gdal::{Dataset, DatasetOptions};
+use gdal_sys::{GDALColorInterp, GDALGetRasterColorInterpretation, OSRAxisMappingStrategy};
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 
 pub struct GdalSource {
     ds: Dataset,
+    // The GDAL path, used to open overviews
+    path: String,
+    // Overview datasets by level, opened on demand
+    overviews: RefCell<HashMap<usize, Dataset>>,
+}
+
+fn open_dataset(path: &str) -> Result<Dataset> {
+    Dataset::open(path).map_err(|e| Error::DatasetNotFound(format!("{}: {}", path, e)))
+}
+
+// Returns the index of the coarsest overview whose resolution is still at least as fine as
+// `target_resolution`, or None if the full resolution should be used. `overview_factors` are
+// the decimation factors of the overviews, from finest to coarsest
+fn select_overview(
+    native_resolution: f64,
+    overview_factors: &[f64],
+    target_resolution: f64,
+) -> Option<usize> {
+    overview_factors
+        .iter()
+        .take_while(|factor| native_resolution * *factor <= target_resolution)
+        .count()
+        .checked_sub(1)
 }
 
 impl GdalSource {
     pub fn from_file(filename: &str) -> Result<GdalSource> {
-        let ds = Dataset::open(filename)?;
-        Ok(GdalSource { ds })
+        GdalSource::open(filename)
     }
 
     pub fn from_blobstore(blobname: &str) -> Result<GdalSource> {
         let mut vsi_path = "/vsis3/".to_owned();
         vsi_path.push_str(blobname);
-        let ds = Dataset::open(vsi_path.as_str())?;
-        Ok(GdalSource { ds })
+        GdalSource::open(&vsi_path)
+    }
+
+    fn open(path: &str) -> Result<GdalSource> {
+        let ds = open_dataset(path)?;
+        Ok(GdalSource {
+            ds,
+            path: path.to_string(),
+            overviews: RefCell::new(HashMap::new()),
+        })
+    }
+
+    // The overview level matching the resolution of the target, if any
+    fn overview_level(&self, target_ds: &Dataset) -> Result<Option<usize>> {
+        let band = self.ds.rasterband(1)?;
+        let num_overviews = band.overview_count()?;
+        if num_overviews == 0 {
+            return Ok(None);
+        }
+        let (width, _) = self.ds.raster_size();
+        let mut overview_factors = vec![];
+        for i in 0..num_overviews {
+            let (overview_width, _) = band.overview(i as isize)?.size();
+            overview_factors.push(width as f64 / overview_width as f64);
+        }
+
+        // The target resolution, in the units of the source CRS
+        let source_srs = self.ds.spatial_ref()?;
+        source_srs.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let target_srs = target_ds.spatial_ref()?;
+        target_srs.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let transform = CoordTransform::new(&target_srs, &source_srs)?;
+        let bbox = raster_local_bbox(target_ds)?.transform(&transform)?;
+        let (target_width, target_height) = target_ds.raster_size();
+        let target_resolution = ((bbox.xmax - bbox.xmin) / target_width as f64)
+            .min((bbox.ymax - bbox.ymin) / target_height as f64);
+
+        let native_resolution = self.ds.geo_transform()?[1].abs();
+        Ok(select_overview(
+            native_resolution,
+            &overview_factors,
+            target_resolution,
+        ))
     }
 }
 
@@ -28,14 +97,94 @@ impl Source for GdalSource {
         self.ds.raster_count() as usize
     }
 
-    fn reproject_to(&self, target_ds: &Dataset) -> Result<()> {
-        match gdal::raster::reproject(&self.ds, target_ds) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(GdalError(e)),
+    fn reproject_to(&self, target_ds: &Dataset, options: &ReprojectOptions) -> Result<()> {
+        // Warping from the full resolution at low zooms would read (and for remote rasters,
+        // download) far more data than needed
+        let level = match self.overview_level(target_ds) {
+            Ok(level) => level,
+            Err(e) => {
+                log::warn!("{}: failed to select overview: {:?}", self.path, e);
+                None
+            }
+        };
+        let level = match level {
+            Some(level) => level,
+            None => {
+                log::debug!("{}: using full resolution", self.path);
+                return reproject(&self.ds, target_ds, options);
+            }
+        };
+        log::debug!("{}: using overview {}", self.path, level);
+        let mut overviews = self.overviews.borrow_mut();
+        if let Entry::Vacant(entry) = overviews.entry(level) {
+            let open_option = format!("OVERVIEW_LEVEL={}", level);
+            let ds = Dataset::open_ex(
+                &self.path,
+                DatasetOptions {
+                    open_options: Some(&[open_option.as_str()]),
+                    ..Default::default()
+                },
+            )?;
+            entry.insert(ds);
+        }
+        reproject(&overviews[&level], target_ds, options)
+    }
+
+    fn nodata(&self) -> Vec<Option<f64>> {
+        (1..=self.ds.raster_count())
+            .map(|i| self.ds.rasterband(i).ok().and_then(|b| b.no_data_value()))
+            .collect()
+    }
+
+    fn alpha_band(&self) -> Option<usize> {
+        (1..=self.ds.raster_count())
+            .find(|i| match self.ds.rasterband(*i) {
+                Ok(band) => {
+                    let interp = unsafe { GDALGetRasterColorInterpretation(band.c_rasterband()) };
+                    interp == GDALColorInterp::GCI_AlphaBand
+                }
+                Err(_) => false,
+            })
+            .map(|i| i as usize - 1)
+    }
+
+    fn has_mask(&self) -> bool {
+        // Alpha and nodata are also exposed as masks, but they are handled by their own
+        match self.ds.rasterband(1).and_then(|band| band.mask_flags()) {
+            Ok(flags) => {
+                flags.is_per_dataset()
+                    && !flags.is_all_valid()
+                    && !flags.is_alpha()
+                    && !flags.is_nodata()
+            }
+            Err(_) => false,
         }
     }
 
     fn wgs84_bbox(&self) -> Result<BoundingBox> {
         wgs84_bbox(&self.ds)
     }
+
+    fn native_resolution(&self) -> Option<f64> {
+        let bbox = raster_projected_bbox(&self.ds, 3857).ok()?;
+        let (width, height) = self.ds.raster_size();
+        Some(((bbox.xmax - bbox.xmin) / width as f64).min((bbox.ymax - bbox.ymin) / height as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_overview() {
+        let factors = [2.0, 4.0, 8.0];
+        // Finer than the native resolution
+        assert_eq!(select_overview(10.0, &factors, 5.0), None);
+        assert_eq!(select_overview(10.0, &factors, 15.0), None);
+        assert_eq!(select_overview(10.0, &factors, 20.0), Some(0));
+        assert_eq!(select_overview(10.0, &factors, 79.0), Some(1));
+        assert_eq!(select_overview(10.0, &factors, 1000.0), Some(2));
+        assert_eq!(select_overview(10.0, &[], 1000.0), None);
+    }
 }
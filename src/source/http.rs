@@ -0,0 +1,106 @@
+//! Minimal blocking HTTP client used by the sources that talk to upstream servers
+use crate::utils::{is_timeout, Error, Result};
+use std::io::Read;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+// Upper bound on the size of a response we are willing to load in memory
+const MAX_RESPONSE_SIZE: u64 = 64 * 1024 * 1024;
+
+static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+
+pub struct Response {
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Whether the response has the given MIME type, ignoring the parameters of its content type
+    /// (e.g. `image/png; mode=8bit`) and case
+    pub fn has_mime_type(&self, mime_type: &str) -> bool {
+        self.content_type
+            .split(';')
+            .next()
+            .is_some_and(|essence| essence.trim().eq_ignore_ascii_case(mime_type))
+    }
+}
+
+pub fn get(url: &str) -> Result<Response> {
+    read_response(url, call(url).map_err(|e| *e)?)
+}
+
+/// Like `get` but returns None if the resource doesn't exist (404), which tile servers commonly
+/// use for empty tiles
+pub fn get_optional(url: &str) -> Result<Option<Response>> {
+    match call(url) {
+        Ok(response) => Ok(Some(read_response(url, response)?)),
+        Err(e) if matches!(*e, ureq::Error::Status(404, _)) => Ok(None),
+        Err(e) => Err((*e).into()),
+    }
+}
+
+// ureq::Error is large (it holds the whole response for status errors), so it is boxed
+fn call(url: &str) -> std::result::Result<ureq::Response, Box<ureq::Error>> {
+    let agent = AGENT.get_or_init(|| ureq::AgentBuilder::new().timeout(TIMEOUT).build());
+    log::debug!("GET {}", url);
+    agent.get(url).call().map_err(Box::new)
+}
+
+fn read_response(url: &str, response: ureq::Response) -> Result<Response> {
+    let content_type = response.content_type().to_string();
+    let mut body = vec![];
+    response
+        .into_reader()
+        .take(MAX_RESPONSE_SIZE)
+        .read_to_end(&mut body)
+        .map_err(|e| {
+            let message = format!("Failed to read {}: {}", url, e);
+            if is_timeout(Some(&e)) {
+                Error::UpstreamTimeout(message)
+            } else {
+                Error::UpstreamError(message)
+            }
+        })?;
+    Ok(Response { content_type, body })
+}
+
+/// A tiny HTTP server to stub upstream services in tests
+#[cfg(test)]
+pub mod stub_server {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Start a server on a random local port and return its base url (`http://127.0.0.1:port`).
+    /// `handler` is called with the request path (including the query string) and returns the
+    /// content type and body of the response. The server lives until the test process exits.
+    pub fn serve(handler: fn(&str) -> (&'static str, Vec<u8>)) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                // Skip headers
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let path = request_line.split(' ').nth(1).unwrap_or("/");
+                let (content_type, body) = handler(path);
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    content_type,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+}
@@ -0,0 +1,398 @@
+use crate::bbox::BoundingBox;
+use crate::ds_utils::{dataset_from_image, reproject, ReprojectOptions};
+use crate::raster::raster_local_bbox;
+use crate::source::http;
+use crate::source::Source;
+use crate::utils::{Error, ImageData, Result};
+use crate::window::Window;
+use crate::wms::epsg_swaps_axes;
+use gdal::spatial_ref::{CoordTransform, SpatialRef};
+use gdal::Dataset;
+use gdal_sys::OSRAxisMappingStrategy;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use url::Url;
+
+const WMS_VERSION: &str = "1.3.0";
+
+// Sources are opened for every input of every tile, so the parsed capabilities are kept for a
+// while instead of being requested from the server each time
+const CAPABILITIES_TTL: Duration = Duration::from_secs(300);
+const MAX_CACHED_SOURCES: usize = 256;
+
+static SOURCES: OnceLock<Mutex<LruCache<String, (Instant, WmsSource)>>> = OnceLock::new();
+
+/// A layer of an upstream WMS server. GetMap requests are made in the CRS of the target dataset
+/// if the server supports it and in WGS84 otherwise, and the result is then warped to the target
+#[derive(Clone)]
+pub struct WmsSource {
+    url: Url,
+    layer: String,
+    // CRS supported by the layer, as advertised in the capabilities (e.g. "EPSG:3857")
+    crs: Vec<String>,
+    bbox: BoundingBox,
+}
+
+impl WmsSource {
+    /// `url` is the service url with the layer to use as the LAYERS query parameter, for example
+    /// `https://example.com/wms?LAYERS=basemap`. Other query parameters (e.g. `map=` for
+    /// MapServer) are kept and passed along with every request
+    pub fn from_url(url: &str) -> Result<WmsSource> {
+        let mut url =
+            Url::parse(url).map_err(|e| Error::InvalidPath(format!("WMS url {}: {}", url, e)))?;
+        let mut layer = None;
+        let mut params = vec![];
+        for (key, value) in url.query_pairs() {
+            if key.eq_ignore_ascii_case("LAYERS") {
+                layer = Some(value.to_string());
+            } else {
+                params.push((key.to_string(), value.to_string()));
+            }
+        }
+        let layer = layer
+            .ok_or_else(|| Error::InvalidPath(format!("Missing LAYERS in WMS url {}", url)))?;
+        url.set_query(None);
+        if !params.is_empty() {
+            url.query_pairs_mut().extend_pairs(params);
+        }
+
+        let response = http::get(&request_url(
+            &url,
+            &[
+                ("SERVICE", "WMS"),
+                ("VERSION", WMS_VERSION),
+                ("REQUEST", "GetCapabilities"),
+            ],
+        ))?;
+        let (crs, bbox) =
+            parse_layer_capabilities(&String::from_utf8_lossy(&response.body), &layer)?;
+        Ok(WmsSource {
+            url,
+            layer,
+            crs,
+            bbox,
+        })
+    }
+
+    /// Like `from_url`, but the sources are cached per url for `CAPABILITIES_TTL`
+    pub fn from_url_cached(url: &str) -> Result<WmsSource> {
+        let sources = SOURCES.get_or_init(|| {
+            Mutex::new(LruCache::new(
+                NonZeroUsize::new(MAX_CACHED_SOURCES).unwrap(),
+            ))
+        });
+        if let Some((since, source)) = sources.lock().unwrap().get(url) {
+            if since.elapsed() < CAPABILITIES_TTL {
+                return Ok(source.clone());
+            }
+        }
+        // Fetching the capabilities may be slow, so don't hold the lock
+        let source = WmsSource::from_url(url)?;
+        sources
+            .lock()
+            .unwrap()
+            .put(url.to_string(), (Instant::now(), source.clone()));
+        Ok(source)
+    }
+
+    // Returns the CRS to use in the GetMap request together with the corresponding window
+    fn request_window(&self, target_ds: &Dataset) -> Result<(String, Window)> {
+        let (width, height) = target_ds.raster_size();
+        let target_srs = target_ds.spatial_ref()?;
+        target_srs.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let target_bbox = raster_local_bbox(target_ds)?;
+
+        if let Ok(epsg) = target_srs.auth_code() {
+            let crs = format!("EPSG:{}", epsg);
+            if self.crs.contains(&crs) {
+                let window = Window {
+                    bbox: target_bbox,
+                    epsg: epsg as u32,
+                    width,
+                    height,
+                };
+                return Ok((crs, window));
+            }
+        }
+
+        let crs = ["CRS:84", "EPSG:4326"]
+            .into_iter()
+            .find(|crs| self.crs.iter().any(|c| c == crs))
+            .ok_or_else(|| {
+                Error::UpstreamError(format!(
+                    "WMS layer {} supports neither the target CRS nor WGS84",
+                    self.layer
+                ))
+            })?;
+        let wgs84 = SpatialRef::from_epsg(4326)?;
+        wgs84.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let transform = CoordTransform::new(&target_srs, &wgs84)?;
+        let window = Window {
+            bbox: target_bbox.transform(&transform)?,
+            epsg: 4326,
+            width,
+            height,
+        };
+        Ok((crs.to_string(), window))
+    }
+}
+
+fn request_url(base: &Url, params: &[(&str, &str)]) -> String {
+    let mut url = base.clone();
+    url.query_pairs_mut().extend_pairs(params);
+    url.to_string()
+}
+
+fn child_text<'a>(node: &roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+    node.children()
+        .find(|c| c.has_tag_name(name))
+        .and_then(|c| c.text())
+        .map(|t| t.trim())
+}
+
+fn parse_geographic_bbox(layer: &roxmltree::Node) -> Option<BoundingBox> {
+    // WMS 1.3.0
+    if let Some(bbox) = layer
+        .children()
+        .find(|c| c.has_tag_name("EX_GeographicBoundingBox"))
+    {
+        let value = |name: &str| child_text(&bbox, name).and_then(|v| v.parse::<f64>().ok());
+        return Some(BoundingBox {
+            xmin: value("westBoundLongitude")?,
+            ymin: value("southBoundLatitude")?,
+            xmax: value("eastBoundLongitude")?,
+            ymax: value("northBoundLatitude")?,
+        });
+    }
+    // WMS 1.1.1
+    if let Some(bbox) = layer
+        .children()
+        .find(|c| c.has_tag_name("LatLonBoundingBox"))
+    {
+        let value = |name: &str| bbox.attribute(name).and_then(|v| v.parse::<f64>().ok());
+        return Some(BoundingBox {
+            xmin: value("minx")?,
+            ymin: value("miny")?,
+            xmax: value("maxx")?,
+            ymax: value("maxy")?,
+        });
+    }
+    None
+}
+
+// Returns the CRS and WGS84 bounding box of the given layer. Both are inherited from parent
+// layers if not specified on the layer itself
+fn parse_layer_capabilities(xml: &str, layer: &str) -> Result<(Vec<String>, BoundingBox)> {
+    let doc = roxmltree::Document::parse(xml)
+        .map_err(|e| Error::UpstreamError(format!("Invalid WMS capabilities: {}", e)))?;
+    let layer_node = doc
+        .descendants()
+        .filter(|n| n.has_tag_name("Layer"))
+        .find(|n| child_text(n, "Name") == Some(layer))
+        .ok_or_else(|| Error::InvalidPath(format!("WMS layer {} not found", layer)))?;
+
+    // Note that ancestors() includes the layer itself
+    let layers: Vec<roxmltree::Node> = layer_node
+        .ancestors()
+        .filter(|n| n.has_tag_name("Layer"))
+        .collect();
+    let crs: Vec<String> = layers
+        .iter()
+        .flat_map(|l| l.children())
+        .filter(|c| c.has_tag_name("CRS") || c.has_tag_name("SRS"))
+        .filter_map(|c| c.text())
+        // WMS 1.1.1 allows whitespace-separated lists in SRS
+        .flat_map(|t| t.split_whitespace())
+        .map(|t| t.to_uppercase())
+        .collect();
+    let bbox = layers
+        .iter()
+        .find_map(parse_geographic_bbox)
+        .ok_or_else(|| {
+            Error::UpstreamError(format!("No geographic bounding box for layer {}", layer))
+        })?;
+    Ok((crs, bbox))
+}
+
+impl Source for WmsSource {
+    fn num_bands(&self) -> usize {
+        // GetMap responses are decoded as RGBA
+        4
+    }
+
+    fn alpha_band(&self) -> Option<usize> {
+        Some(3)
+    }
+
+    fn reproject_to(&self, target_ds: &Dataset, options: &ReprojectOptions) -> Result<()> {
+        let (crs, window) = self.request_window(target_ds)?;
+        // WMS 1.3.0 follows the axis order of the CRS, e.g. lat/lon for EPSG:4326
+        let bbox = if crs != "CRS:84" && epsg_swaps_axes(window.epsg)? {
+            let b = &window.bbox;
+            [b.ymin, b.xmin, b.ymax, b.xmax]
+        } else {
+            window.bbox.to_array()
+        };
+        let bbox = bbox.map(|v| v.to_string()).join(",");
+        let width = window.width.to_string();
+        let height = window.height.to_string();
+        let response = http::get(&request_url(
+            &self.url,
+            &[
+                ("SERVICE", "WMS"),
+                ("VERSION", WMS_VERSION),
+                ("REQUEST", "GetMap"),
+                ("LAYERS", &self.layer),
+                ("STYLES", ""),
+                ("CRS", &crs),
+                ("BBOX", &bbox),
+                ("WIDTH", &width),
+                ("HEIGHT", &height),
+                ("FORMAT", "image/png"),
+                ("TRANSPARENT", "TRUE"),
+            ],
+        ))?;
+        // Servers report errors as XML service exceptions with a 200 status
+        if !response.has_mime_type("image/png") {
+            return Err(Error::UpstreamError(format!(
+                "Unexpected GetMap response ({}): {}",
+                response.content_type,
+                String::from_utf8_lossy(&response.body)
+            )));
+        }
+        let image = ImageData::<u8>::from_png(&response.body)?;
+        let ds = dataset_from_image(&image, &window)?;
+        reproject(&ds, target_ds, options)?;
+        Ok(())
+    }
+
+    fn wgs84_bbox(&self) -> Result<BoundingBox> {
+        Ok(self.bbox.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ds_utils::read_ds_at_once;
+    use crate::source::http::stub_server;
+    use gdal::DriverManager;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const CAPABILITIES: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <WMS_Capabilities version="1.3.0" xmlns="http://www.opengis.net/wms">
+          <Capability>
+            <Layer>
+              <Title>root</Title>
+              <CRS>EPSG:3857</CRS>
+              <CRS>CRS:84</CRS>
+              <Layer>
+                <Name>basemap</Name>
+                <EX_GeographicBoundingBox>
+                  <westBoundLongitude>5.9</westBoundLongitude>
+                  <eastBoundLongitude>10.5</eastBoundLongitude>
+                  <southBoundLatitude>45.8</southBoundLatitude>
+                  <northBoundLatitude>47.8</northBoundLatitude>
+                </EX_GeographicBoundingBox>
+              </Layer>
+            </Layer>
+          </Capability>
+        </WMS_Capabilities>
+    "#;
+
+    fn handler(path: &str) -> (&'static str, Vec<u8>) {
+        assert!(path.contains("map=test"));
+        if path.contains("REQUEST=GetCapabilities") {
+            ("text/xml", CAPABILITIES.as_bytes().to_vec())
+        } else {
+            assert!(path.contains("CRS=EPSG%3A3857"));
+            let red = [255, 0, 0, 255].repeat(4 * 4);
+            (
+                "image/png",
+                ImageData::from_vec(4, 4, 4, red).to_png().unwrap(),
+            )
+        }
+    }
+
+    #[test]
+    fn test_from_url() {
+        let url = stub_server::serve(handler);
+        let source = WmsSource::from_url(&format!("{}/wms?map=test&LAYERS=basemap", url)).unwrap();
+        assert_eq!(source.crs, vec!["EPSG:3857", "CRS:84"]);
+        assert_eq!(
+            source.wgs84_bbox().unwrap().to_array(),
+            [5.9, 45.8, 10.5, 47.8]
+        );
+        assert!(WmsSource::from_url(&format!("{}/wms?map=test&LAYERS=other", url)).is_err());
+    }
+
+    static CAPABILITIES_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+    fn counting_handler(path: &str) -> (&'static str, Vec<u8>) {
+        if path.contains("REQUEST=GetCapabilities") {
+            CAPABILITIES_REQUESTS.fetch_add(1, Ordering::SeqCst);
+        }
+        handler(path)
+    }
+
+    #[test]
+    fn test_capabilities_cache() {
+        let url = stub_server::serve(counting_handler);
+        let path = format!("wms:{}/wms?map=test&LAYERS=basemap", url);
+        for _ in 0..2 {
+            let source = crate::source::open_source(&path).unwrap();
+            assert_eq!(
+                source.wgs84_bbox().unwrap().to_array(),
+                [5.9, 45.8, 10.5, 47.8]
+            );
+        }
+        assert_eq!(CAPABILITIES_REQUESTS.load(Ordering::SeqCst), 1);
+    }
+
+    // Same as handler, but with parameters in the content type of the map like MapServer
+    fn handler_with_content_type_params(path: &str) -> (&'static str, Vec<u8>) {
+        match handler(path) {
+            ("image/png", body) => ("Image/PNG; mode=8bit", body),
+            response => response,
+        }
+    }
+
+    // Renders the basemap of the server into a small EPSG:3857 window
+    fn render(url: &str) -> Result<ImageData<f64>> {
+        let source = WmsSource::from_url(&format!("{}/wms?map=test&LAYERS=basemap", url))?;
+        let window = Window {
+            bbox: BoundingBox {
+                xmin: 740000.0,
+                ymin: 5900000.0,
+                xmax: 740400.0,
+                ymax: 5900400.0,
+            },
+            epsg: 3857,
+            width: 4,
+            height: 4,
+        };
+        let srs = SpatialRef::from_epsg(3857).unwrap();
+        let mut ds = DriverManager::get_driver_by_name("MEM")
+            .unwrap()
+            .create_with_band_type::<f64, _>("", 4, 4, 4)
+            .unwrap();
+        ds.set_geo_transform(&window.geo_transform()).unwrap();
+        ds.set_spatial_ref(&srs).unwrap();
+        source.reproject_to(&ds, &ReprojectOptions::default())?;
+        read_ds_at_once(&ds)
+    }
+
+    #[test]
+    fn test_reproject_to() {
+        let image = render(&stub_server::serve(handler)).unwrap();
+        assert_eq!(image.pixel_data(1, 1), &[255.0, 0.0, 0.0, 255.0]);
+    }
+
+    #[test]
+    fn test_content_type_params() {
+        let image = render(&stub_server::serve(handler_with_content_type_params)).unwrap();
+        assert_eq!(image.pixel_data(1, 1), &[255.0, 0.0, 0.0, 255.0]);
+    }
+}
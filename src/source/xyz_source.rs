@@ -0,0 +1,240 @@
+use crate::bbox::BoundingBox;
+use crate::ds_utils::{dataset_from_image, reproject, ReprojectOptions};
+use crate::raster::raster_local_bbox;
+use crate::source::http;
+use crate::source::Source;
+use crate::utils::{Error, ImageData, Result};
+use crate::window::Window;
+use crate::xyz::{resolution_at_zoom, tile_window, tiles_covering, zoom_for_resolution};
+use crate::xyz::{TileCoords, TILE_SIZE};
+use gdal::spatial_ref::{CoordTransform, SpatialRef};
+use gdal::Dataset;
+use gdal_sys::OSRAxisMappingStrategy;
+
+const DEFAULT_MAX_ZOOM: u64 = 18;
+// Upper bound on the number of upstream tiles fetched for a single window. If more would be
+// needed at the selected zoom, we go up the pyramid instead
+const MAX_TILES: usize = 64;
+// Latitude bounds of the Web Mercator square
+const MAX_LATITUDE: f64 = 85.0511287798066;
+
+/// An upstream XYZ tile server serving Web Mercator PNG tiles. The upstream tiles covering the
+/// requested window are fetched at a zoom level matching the target resolution, mosaicked and
+/// then warped to the target
+pub struct XyzSource {
+    url_template: String,
+    max_zoom: u64,
+}
+
+impl XyzSource {
+    /// `url` is a template with {z}, {x} and {y} placeholders, optionally followed by a
+    /// `#maxzoom=N` fragment, for example `https://example.com/{z}/{x}/{y}.png#maxzoom=15`
+    pub fn from_url(url: &str) -> Result<XyzSource> {
+        let (url_template, max_zoom) = match url.split_once('#') {
+            Some((template, fragment)) => {
+                let max_zoom = fragment
+                    .strip_prefix("maxzoom=")
+                    .and_then(|z| z.parse::<u64>().ok())
+                    .ok_or_else(|| {
+                        Error::InvalidPath(format!("Invalid XYZ url fragment: {}", fragment))
+                    })?;
+                (template, max_zoom)
+            }
+            None => (url, DEFAULT_MAX_ZOOM),
+        };
+        if !["{z}", "{x}", "{y}"]
+            .iter()
+            .all(|p| url_template.contains(p))
+        {
+            return Err(Error::InvalidPath(format!(
+                "XYZ url must contain {{z}}, {{x}} and {{y}}: {}",
+                url_template
+            )));
+        }
+        Ok(XyzSource {
+            url_template: url_template.to_string(),
+            max_zoom,
+        })
+    }
+
+    fn tile_url(&self, coords: &TileCoords) -> String {
+        self.url_template
+            .replace("{z}", &coords.zoom.to_string())
+            .replace("{x}", &coords.x.to_string())
+            .replace("{y}", &coords.y.to_string())
+    }
+
+    // Fetch the tiles covering `bbox` (in EPSG:3857) at the given zoom and mosaic them. Missing
+    // tiles are left transparent
+    fn fetch_mosaic(&self, bbox: &BoundingBox, zoom: u64) -> Result<(ImageData<u8>, Window)> {
+        let (xs, ys) = tiles_covering(bbox, zoom);
+        let tile_size = TILE_SIZE as usize;
+        let width = xs.clone().count() * tile_size;
+        let height = ys.clone().count() * tile_size;
+        let mut mosaic = ImageData::<u8>::new(width, height, 4);
+        for (row, y) in ys.clone().enumerate() {
+            for (col, x) in xs.clone().enumerate() {
+                let url = self.tile_url(&TileCoords { x, y, zoom });
+                let response = match http::get_optional(&url)? {
+                    Some(response) => response,
+                    None => continue,
+                };
+                let tile = ImageData::<u8>::from_png(&response.body)?;
+                if tile.width != tile_size || tile.height != tile_size {
+                    return Err(Error::UpstreamError(format!(
+                        "Expected {}x{} tile, got {}x{} for {}",
+                        tile_size, tile_size, tile.width, tile.height, url
+                    )));
+                }
+                for i in 0..tile_size {
+                    let start = ((row * tile_size + i) * width + col * tile_size) * 4;
+                    mosaic.data[start..start + tile_size * 4]
+                        .copy_from_slice(&tile.data[i * tile_size * 4..(i + 1) * tile_size * 4]);
+                }
+            }
+        }
+
+        let top_left = tile_window(
+            &TileCoords {
+                x: *xs.start(),
+                y: *ys.start(),
+                zoom,
+            },
+            tile_size,
+        );
+        let bottom_right = tile_window(
+            &TileCoords {
+                x: *xs.end(),
+                y: *ys.end(),
+                zoom,
+            },
+            tile_size,
+        );
+        let window = Window {
+            bbox: BoundingBox {
+                xmin: top_left.bbox.xmin,
+                ymin: bottom_right.bbox.ymin,
+                xmax: bottom_right.bbox.xmax,
+                ymax: top_left.bbox.ymax,
+            },
+            epsg: 3857,
+            width,
+            height,
+        };
+        Ok((mosaic, window))
+    }
+}
+
+impl Source for XyzSource {
+    fn num_bands(&self) -> usize {
+        // Tiles are decoded as RGBA
+        4
+    }
+
+    fn alpha_band(&self) -> Option<usize> {
+        Some(3)
+    }
+
+    fn reproject_to(&self, target_ds: &Dataset, options: &ReprojectOptions) -> Result<()> {
+        let (width, height) = target_ds.raster_size();
+        let target_srs = target_ds.spatial_ref()?;
+        target_srs.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let web_mercator = SpatialRef::from_epsg(3857)?;
+        web_mercator.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let transform = CoordTransform::new(&target_srs, &web_mercator)?;
+        let bbox = raster_local_bbox(target_ds)?.transform(&transform)?;
+
+        let resolution =
+            ((bbox.xmax - bbox.xmin) / width as f64).min((bbox.ymax - bbox.ymin) / height as f64);
+        let mut zoom = zoom_for_resolution(resolution, self.max_zoom);
+        while zoom > 0 {
+            let (xs, ys) = tiles_covering(&bbox, zoom);
+            if xs.count() * ys.count() <= MAX_TILES {
+                break;
+            }
+            zoom -= 1;
+        }
+        log::debug!(
+            "xyz source: target resolution {:.2}m, using zoom {} ({:.2}m)",
+            resolution,
+            zoom,
+            resolution_at_zoom(zoom)
+        );
+
+        let (mosaic, window) = self.fetch_mosaic(&bbox, zoom)?;
+        let ds = dataset_from_image(&mosaic, &window)?;
+        reproject(&ds, target_ds, options)?;
+        Ok(())
+    }
+
+    fn wgs84_bbox(&self) -> Result<BoundingBox> {
+        Ok(BoundingBox {
+            xmin: -180.0,
+            ymin: -MAX_LATITUDE,
+            xmax: 180.0,
+            ymax: MAX_LATITUDE,
+        })
+    }
+
+    fn native_resolution(&self) -> Option<f64> {
+        Some(resolution_at_zoom(self.max_zoom))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ds_utils::read_ds_at_once;
+    use crate::source::http::stub_server;
+    use gdal::DriverManager;
+
+    fn handler(path: &str) -> (&'static str, Vec<u8>) {
+        // The stub only has tiles at zoom 16
+        assert!(path.starts_with("/tiles/16/"));
+        let green = [0, 255, 0, 255].repeat(256 * 256);
+        (
+            "image/png",
+            ImageData::from_vec(256, 256, 4, green).to_png().unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_from_url() {
+        assert!(XyzSource::from_url("https://example.com/{z}/{x}/{y}.png").is_ok());
+        let source = XyzSource::from_url("https://example.com/{z}/{x}/{y}.png#maxzoom=12").unwrap();
+        assert_eq!(source.max_zoom, 12);
+        assert!(XyzSource::from_url("https://example.com/{z}/{x}.png").is_err());
+    }
+
+    #[test]
+    fn test_reproject_to() {
+        let url = stub_server::serve(handler);
+        let source =
+            XyzSource::from_url(&format!("{}/tiles/{{z}}/{{x}}/{{y}}.png#maxzoom=16", url))
+                .unwrap();
+        // A 4x4 window at 1m resolution, which is finer than the max zoom
+        let window = Window {
+            bbox: BoundingBox {
+                xmin: 740000.0,
+                ymin: 5900000.0,
+                xmax: 740004.0,
+                ymax: 5900004.0,
+            },
+            epsg: 3857,
+            width: 4,
+            height: 4,
+        };
+        let srs = SpatialRef::from_epsg(3857).unwrap();
+        let mut ds = DriverManager::get_driver_by_name("MEM")
+            .unwrap()
+            .create_with_band_type::<f64, _>("", 4, 4, 4)
+            .unwrap();
+        ds.set_geo_transform(&window.geo_transform()).unwrap();
+        ds.set_spatial_ref(&srs).unwrap();
+        source
+            .reproject_to(&ds, &ReprojectOptions::default())
+            .unwrap();
+        let image = read_ds_at_once(&ds).unwrap();
+        assert_eq!(image.pixel_data(2, 2), &[0.0, 255.0, 0.0, 255.0]);
+    }
+}
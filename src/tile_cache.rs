@@ -0,0 +1,155 @@
+//! Caching of rendered (encoded) tiles. Note that tiles are only identified by the script and the
+//! tile coordinates, so cached tiles are not invalidated if the underlying rasters change
+use lru::LruCache;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+pub struct TileCacheKey {
+    // See CustomScript::hash_with_params
+    pub script_hash: String,
+    // Id of the tile matrix set, see tms::TileMatrixSet
+    pub tms: String,
+    // Index of the tile matrix in the set (the zoom level for XYZ tiles)
+    pub z: u64,
+    pub x: u64,
+    pub y: u64,
+    // Size of the tile in pixels for 256x256 tile matrices, see xyz::TILE_SIZES
+    pub tile_size: usize,
+    // The extension of the encoded format, e.g. "png"
+    pub format: String,
+}
+
+impl TileCacheKey {
+    /// A relative path uniquely identifying this tile
+    pub fn to_path(&self) -> String {
+        format!(
+            "{}/{}/{}/{}/{}/{}.{}",
+            self.script_hash, self.tms, self.tile_size, self.z, self.x, self.y, self.format
+        )
+    }
+
+    /// The (quoted) HTTP entity tag for this tile. It doesn't change when the inputs of the
+    /// script are modified, see serve_tile
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.to_path().replace('/', "-"))
+    }
+}
+
+pub trait TileCache: Send + Sync {
+    fn get(&self, key: &TileCacheKey) -> Option<Vec<u8>>;
+    fn put(&self, key: &TileCacheKey, data: &[u8]);
+}
+
+/// A cache that doesn't store anything
+pub struct NoTileCache;
+
+impl TileCache for NoTileCache {
+    fn get(&self, _key: &TileCacheKey) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn put(&self, _key: &TileCacheKey, _data: &[u8]) {}
+}
+
+/// An in-memory cache keeping the `capacity` most recently used tiles
+pub struct MemoryTileCache {
+    tiles: Mutex<LruCache<String, Vec<u8>>>,
+}
+
+impl MemoryTileCache {
+    pub fn new(capacity: NonZeroUsize) -> MemoryTileCache {
+        MemoryTileCache {
+            tiles: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+}
+
+impl TileCache for MemoryTileCache {
+    fn get(&self, key: &TileCacheKey) -> Option<Vec<u8>> {
+        self.tiles.lock().unwrap().get(&key.to_path()).cloned()
+    }
+
+    fn put(&self, key: &TileCacheKey, data: &[u8]) {
+        self.tiles.lock().unwrap().put(key.to_path(), data.to_vec());
+    }
+}
+
+/// A cache storing tiles as files in a directory, laid out as
+/// `{script_hash}/{tms}/{tile_size}/{z}/{x}/{y}.{format}`.
+/// There is no eviction, the directory has to be cleaned up externally
+pub struct DiskTileCache {
+    dir: PathBuf,
+}
+
+impl DiskTileCache {
+    pub fn new(dir: PathBuf) -> DiskTileCache {
+        DiskTileCache { dir }
+    }
+}
+
+impl TileCache for DiskTileCache {
+    fn get(&self, key: &TileCacheKey) -> Option<Vec<u8>> {
+        fs::read(self.dir.join(key.to_path())).ok()
+    }
+
+    fn put(&self, key: &TileCacheKey, data: &[u8]) {
+        let path = self.dir.join(key.to_path());
+        // Write to a temporary file and rename so concurrent readers never see a partial tile
+        let tmp_path = path.with_extension(format!(
+            "{}.tmp{:?}",
+            key.format,
+            std::thread::current().id()
+        ));
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&tmp_path, data))
+            .and_then(|_| fs::rename(&tmp_path, &path));
+        if let Err(e) = result {
+            log::warn!("Failed to cache tile {:?}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(x: u64) -> TileCacheKey {
+        TileCacheKey {
+            script_hash: "abcd".to_string(),
+            tms: "WebMercatorQuad".to_string(),
+            z: 3,
+            x,
+            y: 4,
+            tile_size: 256,
+            format: "png".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_memory_cache() {
+        let cache = MemoryTileCache::new(NonZeroUsize::new(2).unwrap());
+        cache.put(&key(1), &[1]);
+        cache.put(&key(2), &[2]);
+        assert_eq!(cache.get(&key(1)), Some(vec![1]));
+        // 2 is now the least recently used and gets evicted
+        cache.put(&key(3), &[3]);
+        assert_eq!(cache.get(&key(2)), None);
+        assert_eq!(cache.get(&key(1)), Some(vec![1]));
+        assert_eq!(cache.get(&key(3)), Some(vec![3]));
+    }
+
+    #[test]
+    fn test_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("tilemachine_test_{}", std::process::id()));
+        let cache = DiskTileCache::new(dir.clone());
+        assert_eq!(cache.get(&key(1)), None);
+        cache.put(&key(1), &[1, 2, 3]);
+        assert_eq!(cache.get(&key(1)), Some(vec![1, 2, 3]));
+        assert!(dir.join("abcd/WebMercatorQuad/256/3/1/4.png").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+//! TileJSON 3.0 documents describing the XYZ tiles of a script, for clients like MapLibre or
+//! deck.gl: https://github.com/mapbox/tilejson-spec/tree/master/3.0.0
+use crate::bbox::BoundingBox;
+use crate::tms::WEB_MERCATOR_QUAD_MAX_ZOOM;
+use crate::xyz::{resolution_at_zoom, zoom_for_resolution};
+use serde::Serialize;
+
+#[derive(Serialize, Debug)]
+pub struct TileJson {
+    pub tilejson: &'static str,
+    pub tiles: Vec<String>,
+    pub scheme: &'static str,
+    pub bounds: [f64; 4],
+    /// Longitude, latitude and zoom
+    pub center: [f64; 3],
+    pub minzoom: u64,
+    pub maxzoom: u64,
+}
+
+/// `tiles_url` is a template with {z}, {x} and {y} placeholders and `bbox` the WGS84 bounds of
+/// the script. The max zoom is the first one fine enough for `native_resolution` (see
+/// CustomScript::native_resolution), or the finest one if it isn't known
+pub fn tilejson(tiles_url: String, bbox: &BoundingBox, native_resolution: Option<f64>) -> TileJson {
+    let maxzoom = match native_resolution {
+        Some(resolution) => zoom_for_resolution(resolution, WEB_MERCATOR_QUAD_MAX_ZOOM),
+        None => WEB_MERCATOR_QUAD_MAX_ZOOM,
+    };
+    // Center on the first zoom where the bounds fit in a tile. At zoom 0, a tile spans 360
+    // degrees of longitude
+    let span = (bbox.xmax - bbox.xmin) / 360.0;
+    let center_zoom = zoom_for_resolution(resolution_at_zoom(0) * span, maxzoom);
+    TileJson {
+        tilejson: "3.0.0",
+        tiles: vec![tiles_url],
+        scheme: "xyz",
+        bounds: bbox.to_array(),
+        center: [
+            (bbox.xmin + bbox.xmax) / 2.0,
+            (bbox.ymin + bbox.ymax) / 2.0,
+            center_zoom as f64,
+        ],
+        minzoom: 0,
+        maxzoom,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tilejson() {
+        let bbox = BoundingBox {
+            xmin: 174.0,
+            ymin: -41.0,
+            xmax: 175.0,
+            ymax: -40.0,
+        };
+        let url = "http://localhost/tile/xyz/script/{z}/{y}/{x}.png".to_string();
+        let doc = tilejson(url.clone(), &bbox, Some(resolution_at_zoom(15) * 0.9));
+        assert_eq!(doc.tiles, vec![url.clone()]);
+        assert_eq!(doc.maxzoom, 16);
+        assert_eq!(doc.center, [174.5, -40.5, 9.0]);
+        let doc = tilejson(url, &bbox, None);
+        assert_eq!(doc.maxzoom, WEB_MERCATOR_QUAD_MAX_ZOOM);
+    }
+}
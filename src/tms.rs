@@ -0,0 +1,545 @@
+//! Tile matrix sets, as defined by the OGC Two Dimensional Tile Matrix Set standard (2.0):
+//! https://docs.ogc.org/is/17-083r4/17-083r4.html
+//!
+//! WebMercatorQuad (the XYZ grid) and WorldCRS84Quad are built in and other sets (e.g. national
+//! grids) can be loaded from their JSON definition
+use crate::bbox::BoundingBox;
+use crate::utils::{Error, Result};
+use crate::window::Window;
+use crate::xyz::{resolution_at_zoom, TILE_SIZE};
+use gdal::spatial_ref::{CoordTransform, SpatialRef};
+use gdal_sys::OSRAxisMappingStrategy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+pub const WEB_MERCATOR_QUAD: &str = "WebMercatorQuad";
+pub const WORLD_CRS84_QUAD: &str = "WorldCRS84Quad";
+
+/// The finest zoom level of WebMercatorQuad
+pub const WEB_MERCATOR_QUAD_MAX_ZOOM: u64 = 24;
+const WORLD_CRS84_QUAD_MAX_ZOOM: u64 = 17;
+
+// The standardized rendering pixel size (0.28mm) used to compute scale denominators
+const STANDARD_PIXEL_SIZE: f64 = 0.00028;
+// Length of a degree at the equator of the WGS84 ellipsoid, in meters
+const METERS_PER_DEGREE: f64 = 6378137.0 * 2.0 * std::f64::consts::PI / 360.0;
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum CornerOfOrigin {
+    #[default]
+    TopLeft,
+    BottomLeft,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TileMatrix {
+    pub id: String,
+    pub scale_denominator: f64,
+    /// Size of a pixel, in CRS units
+    pub cell_size: f64,
+    #[serde(default)]
+    pub corner_of_origin: CornerOfOrigin,
+    /// In the axis order of the CRS, see `TileMatrixSet::ordered_axes`
+    pub point_of_origin: [f64; 2],
+    pub tile_width: usize,
+    pub tile_height: usize,
+    pub matrix_width: u64,
+    pub matrix_height: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TileMatrixSet {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    /// e.g. `http://www.opengis.net/def/crs/EPSG/0/2056`
+    pub crs: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ordered_axes: Option<Vec<String>>,
+    /// From the coarsest to the finest
+    pub tile_matrices: Vec<TileMatrix>,
+}
+
+// Returns the EPSG code of a CRS given as an OGC URI (or `EPSG:xxxx`). CRS84 is EPSG:4326 with
+// longitude first, which is the axis order windows use anyway
+fn parse_crs(crs: &str) -> Option<u32> {
+    if crs.ends_with("/OGC/1.3/CRS84") || crs == "OGC:CRS84" {
+        return Some(4326);
+    }
+    let code = match crs.split_once("/EPSG/") {
+        // The code follows the version, e.g. EPSG/0/3857
+        Some((_, version_code)) => version_code.rsplit('/').next()?,
+        None => crs.strip_prefix("EPSG:")?,
+    };
+    code.parse().ok()
+}
+
+impl TileMatrixSet {
+    pub fn from_json(json: &str) -> Result<TileMatrixSet> {
+        let tms: TileMatrixSet = serde_json::from_str(json)?;
+        tms.epsg()?;
+        if tms.tile_matrices.is_empty() {
+            return Err(Error::InvalidParameter(format!(
+                "tile matrix set {} has no tile matrix",
+                tms.id
+            )));
+        }
+        Ok(tms)
+    }
+
+    pub fn from_file(path: &Path) -> Result<TileMatrixSet> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| Error::ConfigError(format!("{}: {}", path.display(), e)))?;
+        TileMatrixSet::from_json(&json)
+            .map_err(|e| Error::ConfigError(format!("{}: {:?}", path.display(), e)))
+    }
+
+    /// The EPSG code of the CRS of the set
+    pub fn epsg(&self) -> Result<u32> {
+        parse_crs(&self.crs)
+            .ok_or_else(|| Error::InvalidParameter(format!("unsupported CRS: {}", self.crs)))
+    }
+
+    // Whether the CRS has its northing (or latitude) first, e.g. EPSG:4326
+    fn northing_first(&self) -> bool {
+        let first_axis = self
+            .ordered_axes
+            .as_ref()
+            .and_then(|axes| axes.first())
+            .map(|axis| axis.to_lowercase());
+        matches!(
+            first_axis.as_deref(),
+            Some("lat" | "latitude" | "n" | "northing" | "y")
+        )
+    }
+
+    // The point of origin of the matrix as (x, y), whatever the axis order of the CRS
+    fn point_of_origin(&self, matrix: &TileMatrix) -> (f64, f64) {
+        match matrix.point_of_origin {
+            [y, x] if self.northing_first() => (x, y),
+            [x, y] => (x, y),
+        }
+    }
+
+    /// The top left corner of the matrix, in the axis order of the CRS (as WMTS expects it)
+    pub fn top_left_corner(&self, matrix: &TileMatrix) -> [f64; 2] {
+        let (x, y) = self.point_of_origin(matrix);
+        let y = match matrix.corner_of_origin {
+            CornerOfOrigin::TopLeft => y,
+            CornerOfOrigin::BottomLeft => {
+                y + matrix.cell_size * (matrix.tile_height as u64 * matrix.matrix_height) as f64
+            }
+        };
+        if self.northing_first() {
+            [y, x]
+        } else {
+            [x, y]
+        }
+    }
+
+    /// The CRS as an URN, e.g. `urn:ogc:def:crs:EPSG::3857`
+    pub fn crs_urn(&self) -> Result<String> {
+        if parse_crs(&self.crs) == Some(4326) && !self.northing_first() {
+            return Ok("urn:ogc:def:crs:OGC:1.3:CRS84".to_string());
+        }
+        Ok(format!("urn:ogc:def:crs:EPSG::{}", self.epsg()?))
+    }
+
+    /// The index of the coarsest tile matrix whose pixels are at least as fine as `resolution`
+    /// (in meters, see Source::native_resolution), or of the finest one if none is. Like
+    /// xyz::zoom_for_resolution for WebMercatorQuad
+    pub fn matrix_index_for_resolution(&self, resolution: f64) -> usize {
+        self.tile_matrices
+            .iter()
+            .position(|m| m.scale_denominator * STANDARD_PIXEL_SIZE <= resolution)
+            .unwrap_or(self.tile_matrices.len().saturating_sub(1))
+    }
+
+    /// The columns and rows of the tiles of `matrix` covering `bbox`, which is in the CRS of the
+    /// set (in x/y order). Like xyz::tiles_covering, the max bounds are exclusive
+    pub fn tiles_covering(
+        &self,
+        matrix: &TileMatrix,
+        bbox: &BoundingBox,
+    ) -> (RangeInclusive<u64>, RangeInclusive<u64>) {
+        let (origin_x, origin_y) = self.point_of_origin(matrix);
+        // Distances from the origin, along the columns and rows
+        let (row_min, row_max) = match matrix.corner_of_origin {
+            CornerOfOrigin::TopLeft => (origin_y - bbox.ymax, origin_y - bbox.ymin),
+            CornerOfOrigin::BottomLeft => (bbox.ymin - origin_y, bbox.ymax - origin_y),
+        };
+        let range = |min: f64, max: f64, tile_span: f64, num_tiles: u64| {
+            let max_index = num_tiles.saturating_sub(1) as f64;
+            let first = (min / tile_span).floor().clamp(0.0, max_index) as u64;
+            let last = ((max / tile_span).ceil() - 1.0).clamp(0.0, max_index) as u64;
+            first..=last.max(first)
+        };
+        (
+            range(
+                bbox.xmin - origin_x,
+                bbox.xmax - origin_x,
+                matrix.cell_size * matrix.tile_width as f64,
+                matrix.matrix_width,
+            ),
+            range(
+                row_min,
+                row_max,
+                matrix.cell_size * matrix.tile_height as f64,
+                matrix.matrix_height,
+            ),
+        )
+    }
+
+    /// The tiles covering `wgs84_bbox` in each tile matrix, down to the first one fine enough for
+    /// `native_resolution` (all of them if it isn't known)
+    pub fn limits(
+        &self,
+        wgs84_bbox: &BoundingBox,
+        native_resolution: Option<f64>,
+    ) -> Result<Vec<TileMatrixLimits>> {
+        let wgs84 = SpatialRef::from_epsg(4326)?;
+        wgs84.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let tms_srs = SpatialRef::from_epsg(self.epsg()?)?;
+        tms_srs.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let bbox = wgs84_bbox.transform(&CoordTransform::new(&wgs84, &tms_srs)?)?;
+        let max_index = match native_resolution {
+            Some(resolution) => self.matrix_index_for_resolution(resolution),
+            None => self.tile_matrices.len().saturating_sub(1),
+        };
+        Ok(self
+            .tile_matrices
+            .iter()
+            .take(max_index + 1)
+            .map(|matrix| {
+                let (cols, rows) = self.tiles_covering(matrix, &bbox);
+                TileMatrixLimits {
+                    tile_matrix: matrix.id.clone(),
+                    min_tile_row: *rows.start(),
+                    max_tile_row: *rows.end(),
+                    min_tile_col: *cols.start(),
+                    max_tile_col: *cols.end(),
+                }
+            })
+            .collect())
+    }
+
+    /// The index of the tile matrix with the given id
+    pub fn matrix_index(&self, id: &str) -> Option<usize> {
+        self.tile_matrices.iter().position(|m| m.id == id)
+    }
+
+    /// The window covered by a tile, rendered with `scale` times more pixels than the nominal
+    /// tile size (e.g. 2 for high-DPI tiles)
+    pub fn tile_window(
+        &self,
+        matrix: &TileMatrix,
+        col: u64,
+        row: u64,
+        scale: usize,
+    ) -> Result<Window> {
+        if col >= matrix.matrix_width || row >= matrix.matrix_height {
+            return Err(Error::InvalidPath(format!(
+                "tile {}/{}/{} is outside of {}",
+                matrix.id, row, col, self.id
+            )));
+        }
+        let (origin_x, origin_y) = self.point_of_origin(matrix);
+        let span_x = matrix.cell_size * matrix.tile_width as f64;
+        let span_y = matrix.cell_size * matrix.tile_height as f64;
+        let xmin = origin_x + col as f64 * span_x;
+        let (ymin, ymax) = match matrix.corner_of_origin {
+            CornerOfOrigin::TopLeft => {
+                let ymax = origin_y - row as f64 * span_y;
+                (ymax - span_y, ymax)
+            }
+            CornerOfOrigin::BottomLeft => {
+                let ymin = origin_y + row as f64 * span_y;
+                (ymin, ymin + span_y)
+            }
+        };
+        Ok(Window {
+            bbox: BoundingBox {
+                xmin,
+                ymin,
+                xmax: xmin + span_x,
+                ymax,
+            },
+            epsg: self.epsg()?,
+            width: matrix.tile_width * scale,
+            height: matrix.tile_height * scale,
+        })
+    }
+
+    /// The XYZ grid
+    pub fn web_mercator_quad() -> TileMatrixSet {
+        let origin_shift = resolution_at_zoom(0) * TILE_SIZE as f64 / 2.0;
+        TileMatrixSet {
+            id: WEB_MERCATOR_QUAD.to_string(),
+            title: Some("Google Maps Compatible for the World".to_string()),
+            uri: Some(
+                "http://www.opengis.net/def/tilematrixset/OGC/1.0/WebMercatorQuad".to_string(),
+            ),
+            crs: "http://www.opengis.net/def/crs/EPSG/0/3857".to_string(),
+            ordered_axes: Some(vec!["X".to_string(), "Y".to_string()]),
+            tile_matrices: (0..=WEB_MERCATOR_QUAD_MAX_ZOOM)
+                .map(|zoom| {
+                    let cell_size = resolution_at_zoom(zoom);
+                    TileMatrix {
+                        id: zoom.to_string(),
+                        scale_denominator: cell_size / STANDARD_PIXEL_SIZE,
+                        cell_size,
+                        corner_of_origin: CornerOfOrigin::TopLeft,
+                        point_of_origin: [-origin_shift, origin_shift],
+                        tile_width: TILE_SIZE as usize,
+                        tile_height: TILE_SIZE as usize,
+                        matrix_width: 1 << zoom,
+                        matrix_height: 1 << zoom,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// A longitude/latitude grid with two tiles at zoom 0
+    pub fn world_crs84_quad() -> TileMatrixSet {
+        TileMatrixSet {
+            id: WORLD_CRS84_QUAD.to_string(),
+            title: Some("CRS84 for the World".to_string()),
+            uri: Some(
+                "http://www.opengis.net/def/tilematrixset/OGC/1.0/WorldCRS84Quad".to_string(),
+            ),
+            crs: "http://www.opengis.net/def/crs/OGC/1.3/CRS84".to_string(),
+            ordered_axes: Some(vec!["Lon".to_string(), "Lat".to_string()]),
+            tile_matrices: (0..=WORLD_CRS84_QUAD_MAX_ZOOM)
+                .map(|zoom| {
+                    let cell_size = 180.0 / TILE_SIZE as f64 / (1 << zoom) as f64;
+                    TileMatrix {
+                        id: zoom.to_string(),
+                        scale_denominator: cell_size * METERS_PER_DEGREE / STANDARD_PIXEL_SIZE,
+                        cell_size,
+                        corner_of_origin: CornerOfOrigin::TopLeft,
+                        point_of_origin: [-180.0, 90.0],
+                        tile_width: TILE_SIZE as usize,
+                        tile_height: TILE_SIZE as usize,
+                        matrix_width: 2 << zoom,
+                        matrix_height: 1 << zoom,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The range of the tiles of a tile matrix covering some data, as in the `tileMatrixSetLimits`
+/// of OGC API - Tiles
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TileMatrixLimits {
+    pub tile_matrix: String,
+    pub min_tile_row: u64,
+    pub max_tile_row: u64,
+    pub min_tile_col: u64,
+    pub max_tile_col: u64,
+}
+
+/// The tile matrix sets tiles can be requested in, by id
+pub struct TileMatrixSets {
+    sets: HashMap<String, TileMatrixSet>,
+}
+
+impl TileMatrixSets {
+    /// The built-in sets and the given custom ones, which can override the built-in ones
+    pub fn new(custom: Vec<TileMatrixSet>) -> TileMatrixSets {
+        let mut sets = HashMap::new();
+        for tms in [
+            TileMatrixSet::web_mercator_quad(),
+            TileMatrixSet::world_crs84_quad(),
+        ]
+        .into_iter()
+        .chain(custom)
+        {
+            sets.insert(tms.id.clone(), tms);
+        }
+        TileMatrixSets { sets }
+    }
+
+    /// Looks up a set by id. `xyz` is an alias for WebMercatorQuad
+    pub fn get(&self, id: &str) -> Option<&TileMatrixSet> {
+        let id = if id == "xyz" { WEB_MERCATOR_QUAD } else { id };
+        self.sets.get(id)
+    }
+
+    /// All the sets, sorted by id
+    pub fn all(&self) -> Vec<&TileMatrixSet> {
+        let mut sets: Vec<&TileMatrixSet> = self.sets.values().collect();
+        sets.sort_by(|a, b| a.id.cmp(&b.id));
+        sets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xyz::{tile_window, TileCoords};
+
+    #[test]
+    fn test_web_mercator_quad() {
+        let tms = TileMatrixSet::web_mercator_quad();
+        let matrix = &tms.tile_matrices[4];
+        let window = tms.tile_window(matrix, 3, 5, 2).unwrap();
+        let expected = tile_window(
+            &TileCoords {
+                x: 3,
+                y: 5,
+                zoom: 4,
+            },
+            512,
+        );
+        assert_eq!(window.epsg, 3857);
+        assert_eq!(window.width, 512);
+        for (a, b) in window.bbox.to_array().iter().zip(expected.bbox.to_array()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+        assert!(tms.tile_window(matrix, 16, 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let tms = TileMatrixSet::web_mercator_quad();
+        let bbox = BoundingBox {
+            xmin: 174.0,
+            ymin: -41.0,
+            xmax: 175.0,
+            ymax: -40.0,
+        };
+        let limits = tms
+            .limits(&bbox, Some(resolution_at_zoom(15) * 0.9))
+            .unwrap();
+        // Same max zoom as TileJSON
+        assert_eq!(limits.len(), 17);
+        let bbox_3857 = BoundingBox {
+            xmin: 19369591.398,
+            ymin: -5012341.664,
+            xmax: 19480910.889,
+            ymax: -4865942.280,
+        };
+        for (zoom, limit) in limits.iter().enumerate() {
+            let (cols, rows) = crate::xyz::tiles_covering(&bbox_3857, zoom as u64);
+            assert_eq!(limit.tile_matrix, zoom.to_string());
+            assert_eq!(
+                (limit.min_tile_col, limit.max_tile_col),
+                (*cols.start(), *cols.end())
+            );
+            assert_eq!(
+                (limit.min_tile_row, limit.max_tile_row),
+                (*rows.start(), *rows.end())
+            );
+        }
+        assert_eq!(tms.limits(&bbox, None).unwrap().len(), 25);
+
+        let tms = TileMatrixSet::world_crs84_quad();
+        let limits = tms.limits(&bbox, None).unwrap();
+        assert_eq!(
+            limits[0],
+            TileMatrixLimits {
+                tile_matrix: "0".to_string(),
+                min_tile_row: 0,
+                max_tile_row: 0,
+                min_tile_col: 1,
+                max_tile_col: 1,
+            }
+        );
+        // Tiles of 180 / 2^7 degrees
+        assert_eq!((limits[7].min_tile_col, limits[7].max_tile_col), (251, 252));
+        assert_eq!((limits[7].min_tile_row, limits[7].max_tile_row), (92, 93));
+    }
+
+    #[test]
+    fn test_world_crs84_quad() {
+        let tms = TileMatrixSet::world_crs84_quad();
+        let window = tms.tile_window(&tms.tile_matrices[0], 1, 0, 1).unwrap();
+        assert_eq!(window.epsg, 4326);
+        assert_eq!(window.bbox.to_array(), [0.0, -90.0, 180.0, 90.0]);
+        assert_eq!(tms.crs_urn().unwrap(), "urn:ogc:def:crs:OGC:1.3:CRS84");
+    }
+
+    #[test]
+    fn test_custom_tms() {
+        // A single level of the Swiss LV95 grid, with the northing first as in some definitions
+        let tms = TileMatrixSet::from_json(
+            r#"{
+                "id": "LV95",
+                "crs": "http://www.opengis.net/def/crs/EPSG/0/2056",
+                "orderedAxes": ["N", "E"],
+                "tileMatrices": [{
+                    "id": "0",
+                    "scaleDenominator": 14285750.5715,
+                    "cellSize": 4000,
+                    "pointOfOrigin": [1350000, 2420000],
+                    "tileWidth": 256,
+                    "tileHeight": 256,
+                    "matrixWidth": 1,
+                    "matrixHeight": 1
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(tms.matrix_index("0"), Some(0));
+        let window = tms.tile_window(&tms.tile_matrices[0], 0, 0, 1).unwrap();
+        assert_eq!(window.epsg, 2056);
+        assert_eq!(
+            window.bbox.to_array(),
+            [2420000.0, 326000.0, 3444000.0, 1350000.0]
+        );
+
+        assert!(TileMatrixSet::from_json(
+            r#"{"id": "a", "crs": "http://www.opengis.net/def/crs/IAU/0/1000", "tileMatrices": []}"#
+        )
+        .is_err());
+
+        assert_eq!(
+            tms.top_left_corner(&tms.tile_matrices[0]),
+            [1350000.0, 2420000.0]
+        );
+        assert_eq!(tms.crs_urn().unwrap(), "urn:ogc:def:crs:EPSG::2056");
+
+        let sets = TileMatrixSets::new(vec![tms]);
+        assert_eq!(sets.get("xyz").unwrap().id, WEB_MERCATOR_QUAD);
+        assert_eq!(sets.get("LV95").unwrap().epsg().unwrap(), 2056);
+        assert!(sets.get("foo").is_none());
+        assert_eq!(sets.all().len(), 3);
+    }
+
+    #[test]
+    fn test_config_tms() {
+        let tms = TileMatrixSet::from_json(include_str!("../config/tms/lv95.json")).unwrap();
+        let window = tms.tile_window(&tms.tile_matrices[0], 0, 0, 1).unwrap();
+        assert_eq!(window.bbox.xmin, 2420000.0);
+        let tms = TileMatrixSet::from_json(include_str!(
+            "../config/tms/arctic_polar_stereographic.json"
+        ))
+        .unwrap();
+        assert_eq!(tms.epsg().unwrap(), 3413);
+    }
+
+    #[test]
+    fn test_parse_crs() {
+        assert_eq!(
+            parse_crs("http://www.opengis.net/def/crs/EPSG/0/3413"),
+            Some(3413)
+        );
+        assert_eq!(parse_crs("EPSG:2056"), Some(2056));
+        assert_eq!(
+            parse_crs("http://www.opengis.net/def/crs/OGC/1.3/CRS84"),
+            Some(4326)
+        );
+        assert_eq!(parse_crs("foo"), None);
+    }
+}
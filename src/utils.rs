@@ -1,15 +1,50 @@
 use handlebars::RenderError;
+use std::collections::HashMap;
+use std::fmt;
 use std::io::BufWriter;
+use std::sync::{Mutex, OnceLock};
 
 /// Custom error type for tilemachine
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A JS exception thrown while compiling or running a script
+#[derive(Debug)]
+pub struct ScriptException {
+    pub message: String,
+    // 1-based position of the error in the script, if known
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    // The offending source line with the error underlined
+    pub source_line: Option<String>,
+}
+
+impl fmt::Display for ScriptException {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "script:{}: {}", line, self.message)?,
+            None => write!(f, "{}", self.message)?,
+        }
+        if let Some(source_line) = &self.source_line {
+            write!(f, "\n{}", source_line)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum ScriptError {
     NotEnoughinputs,
-    CompilationError(String),
-    RuntimeError(String),
+    CompilationError(ScriptException),
+    RuntimeError(ScriptException),
     InvalidReturnType,
+    // A pixel mode script returned something else than an array of numbers of an accepted
+    // length for the pixel at (row, column)
+    InvalidPixelValue {
+        row: usize,
+        column: usize,
+        expected: String,
+        value: String,
+    },
 }
 
 #[derive(Debug)]
@@ -19,6 +54,24 @@ pub enum Error {
     ScriptError(ScriptError),
     HandlebarsError(RenderError),
     InvalidPath(String),
+    InvalidParameter(String),
+    // A dataset that couldn't be opened
+    DatasetNotFound(String),
+    // A script ID that isn't registered, see script_store
+    ScriptNotFound(String),
+    // Failure to read or write the script store
+    StorageError(String),
+    UpstreamError(String),
+    UpstreamTimeout(String),
+    PngDecodingError(png::DecodingError),
+    PngEncodingError(png::EncodingError),
+    // Failure to encode an output format other than PNG
+    EncodingError(String),
+    RenderPoolSaturated,
+    // All the dataset handles allowed by the dataset pool are in use
+    DatasetPoolExhausted,
+    RenderPoolPanic,
+    ConfigError(String),
 }
 
 impl From<serde_json::Error> for Error {
@@ -39,6 +92,36 @@ impl From<RenderError> for Error {
     }
 }
 
+impl From<png::DecodingError> for Error {
+    fn from(value: png::DecodingError) -> Self {
+        Error::PngDecodingError(value)
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(value: png::EncodingError) -> Self {
+        Error::PngEncodingError(value)
+    }
+}
+
+impl From<ureq::Error> for Error {
+    fn from(value: ureq::Error) -> Self {
+        match &value {
+            ureq::Error::Transport(t) if is_timeout(std::error::Error::source(t)) => {
+                Error::UpstreamTimeout(value.to_string())
+            }
+            _ => Error::UpstreamError(value.to_string()),
+        }
+    }
+}
+
+/// Whether an HTTP client error is a timeout: ureq reports them as io errors of kind TimedOut
+pub fn is_timeout(error: Option<&(dyn std::error::Error + 'static)>) -> bool {
+    error
+        .and_then(|e| e.downcast_ref::<std::io::Error>())
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut)
+}
+
 pub struct ImageData<T> {
     pub width: usize,
     pub height: usize,
@@ -74,18 +157,72 @@ impl<T: Default + Clone> ImageData<T> {
     }
 }
 
+// Encoded transparent PNGs by (width, height)
+type PngsBySize = HashMap<(usize, usize), Vec<u8>>;
+
 impl ImageData<u8> {
-    /// Encode this image data as PNG and return the bytes
-    pub fn to_png(&self) -> Vec<u8> {
+    /// Decode a PNG image. Whatever the PNG color type, the returned image data is RGBA
+    pub fn from_png(bytes: &[u8]) -> Result<ImageData<u8>> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf)?;
+        buf.truncate(info.buffer_size());
+
+        let (width, height) = (info.width as usize, info.height as usize);
+        let data: Vec<u8> = match info.color_type {
+            png::ColorType::Rgba => buf,
+            png::ColorType::Rgb => buf
+                .chunks(3)
+                .flat_map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+            png::ColorType::GrayscaleAlpha => buf
+                .chunks(2)
+                .flat_map(|p| [p[0], p[0], p[0], p[1]])
+                .collect(),
+            // Indexed is expanded to RGB(A) by the normalize_to_color8 transformation
+            png::ColorType::Grayscale | png::ColorType::Indexed => {
+                buf.iter().flat_map(|v| [*v, *v, *v, 255]).collect()
+            }
+        };
+        Ok(ImageData::from_vec(width, height, 4, data))
+    }
+
+    /// Whether all the pixels of this RGBA image are fully transparent
+    pub fn is_transparent(&self) -> bool {
+        self.channels == 4 && self.data.chunks_exact(4).all(|rgba| rgba[3] == 0)
+    }
+
+    /// Encode this RGBA image as PNG. Fully transparent images (e.g. tiles outside of the
+    /// inputs) are common, so they are only encoded once per size
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        if self.is_transparent() {
+            static TRANSPARENT_PNGS: OnceLock<Mutex<PngsBySize>> = OnceLock::new();
+            let mut pngs = TRANSPARENT_PNGS
+                .get_or_init(Default::default)
+                .lock()
+                .unwrap();
+            if let Some(png) = pngs.get(&(self.width, self.height)) {
+                return Ok(png.clone());
+            }
+            let png = self.encode_png()?;
+            pngs.insert((self.width, self.height), png.clone());
+            return Ok(png);
+        }
+        self.encode_png()
+    }
+
+    fn encode_png(&self) -> Result<Vec<u8>> {
         let mut out_buf = Vec::new();
         {
             let w = BufWriter::new(&mut out_buf);
             let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
             encoder.set_color(png::ColorType::Rgba);
             encoder.set_depth(png::BitDepth::Eight);
-            let mut writer = encoder.write_header().unwrap();
-            writer.write_image_data(&self.data).unwrap();
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&self.data)?;
         }
-        out_buf
+        Ok(out_buf)
     }
 }
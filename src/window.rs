@@ -0,0 +1,380 @@
+use crate::bbox::BoundingBox;
+use crate::ds_utils::{read_ds_at_once, ReprojectOptions};
+use crate::source::Source;
+use crate::utils::{Error, ImageData, Result};
+use gdal::raster::Buffer;
+use gdal::spatial_ref::{CoordTransform, SpatialRef};
+use gdal::DriverManager;
+use gdal_sys::OSRAxisMappingStrategy;
+
+/// A georeferenced pixel grid: `width` x `height` pixels covering `bbox`, which is expressed
+/// in the CRS identified by `epsg` (using the traditional GIS x/y axis order)
+pub struct Window {
+    pub bbox: BoundingBox,
+    pub epsg: u32,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Window {
+    /// The GDAL geotransform of this window (north-up, no rotation)
+    pub fn geo_transform(&self) -> [f64; 6] {
+        let pixel_width = (self.bbox.xmax - self.bbox.xmin) / self.width as f64;
+        let pixel_height = (self.bbox.ymax - self.bbox.ymin) / self.height as f64;
+        [
+            self.bbox.xmin,
+            pixel_width,
+            0.0,
+            self.bbox.ymax,
+            0.0,
+            -pixel_height,
+        ]
+    }
+
+    /// The bounding box of the window in WGS84 (longitude, latitude)
+    pub fn wgs84_bbox(&self) -> Result<BoundingBox> {
+        let window_srs = SpatialRef::from_epsg(self.epsg)?;
+        window_srs.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let wgs84 = SpatialRef::from_epsg(4326)?;
+        wgs84.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+        let transform = CoordTransform::new(&window_srs, &wgs84)?;
+        self.bbox.transform(&transform)
+    }
+}
+
+// Whether the footprint of the source overlaps the window by at least a pixel in one direction,
+// as smaller overlaps are invisible. Errs on the side of true if either footprint can't be
+// computed
+fn source_visible(source: &dyn Source, window: &Window) -> bool {
+    match (source.wgs84_bbox(), window.wgs84_bbox()) {
+        (Ok(source_bbox), Ok(window_bbox)) => match source_bbox.intersection(&window_bbox) {
+            Some(overlap) => {
+                let pixel_width = (window_bbox.xmax - window_bbox.xmin) / window.width as f64;
+                let pixel_height = (window_bbox.ymax - window_bbox.ymin) / window.height as f64;
+                overlap.xmax - overlap.xmin >= pixel_width
+                    || overlap.ymax - overlap.ymin >= pixel_height
+            }
+            None => false,
+        },
+        _ => true,
+    }
+}
+
+/// Reproject the given source into the window and return its pixels, together with the validity
+/// of each pixel. Only the bands selected in `options` are read. Pixels outside of the source,
+/// equal to the nodata value of one of the bands, fully transparent or masked out are invalid
+/// and have all their bands set to NaN
+pub fn extract_window(
+    source: &dyn Source,
+    window: &Window,
+    options: &ReprojectOptions,
+) -> Result<(ImageData<f64>, Vec<bool>)> {
+    let window_srs = SpatialRef::from_epsg(window.epsg)?;
+    window_srs.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
+    let selected = match &options.bands {
+        Some(bands) => bands.clone(),
+        None => (1..=source.num_bands()).collect(),
+    };
+    if let Some(band) = selected
+        .iter()
+        .find(|b| **b == 0 || **b > source.num_bands())
+    {
+        return Err(Error::InvalidParameter(format!(
+            "band {} requested but the source has {} bands",
+            band,
+            source.num_bands()
+        )));
+    }
+    let num_bands = selected.len();
+    let num_pixels = window.width * window.height;
+    if !source_visible(source, window) {
+        log::debug!("skipping source not visible in the window");
+        let image = ImageData::from_vec(
+            window.width,
+            window.height,
+            num_bands,
+            vec![f64::NAN; num_pixels * num_bands],
+        );
+        return Ok((image, vec![false; num_pixels]));
+    }
+
+    // The alpha band is needed for the validity even if it isn't selected, in which case it is
+    // warped as an extra band that is dropped afterwards
+    let mut warped = selected.clone();
+    let alpha_index =
+        source
+            .alpha_band()
+            .map(|alpha| match warped.iter().position(|b| *b == alpha + 1) {
+                Some(index) => index,
+                None => {
+                    warped.push(alpha + 1);
+                    warped.len() - 1
+                }
+            });
+    let source_nodata = source.nodata();
+    let mut nodata: Vec<Option<f64>> = warped
+        .iter()
+        .map(|b| source_nodata.get(b - 1).copied().flatten())
+        .collect();
+    // Likewise the mask is warped as the last band, see ds_utils::select_bands
+    let has_mask = source.has_mask();
+    let mask_index = if has_mask {
+        nodata.push(None);
+        Some(warped.len())
+    } else {
+        None
+    };
+    let num_warped = nodata.len();
+
+    let drv = DriverManager::get_driver_by_name("MEM")?;
+    let mut window_ds = drv.create_with_band_type::<f64, _>(
+        "",
+        window.width as isize,
+        window.height as isize,
+        num_warped as isize,
+    )?;
+
+    window_ds.set_geo_transform(&window.geo_transform())?;
+    window_ds.set_spatial_ref(&window_srs)?;
+    // Pixels not covered by the source are left untouched by the warp, so they stay NaN
+    let nan = vec![f64::NAN; num_pixels];
+    for i in 1..=num_warped {
+        let mut band = window_ds.rasterband(i as isize)?;
+        band.set_no_data_value(Some(f64::NAN))?;
+        band.write(
+            (0, 0),
+            (window.width, window.height),
+            &Buffer::new((window.width, window.height), nan.clone()),
+        )?;
+    }
+
+    let warp_options = ReprojectOptions {
+        bands: Some(warped),
+        mask: has_mask,
+        ..options.clone()
+    };
+    source.reproject_to(&window_ds, &warp_options)?;
+    let mut image = read_ds_at_once(&window_ds)?;
+
+    let mut valid = vec![true; num_pixels];
+    for (pixel, valid) in image
+        .data
+        .chunks_exact_mut(num_warped)
+        .zip(valid.iter_mut())
+    {
+        let is_nodata = pixel.iter().any(|v| v.is_nan())
+            || pixel
+                .iter()
+                .zip(&nodata)
+                .any(|(v, nodata)| Some(*v) == *nodata);
+        let is_transparent = alpha_index.is_some_and(|index| pixel[index] == 0.0);
+        let is_masked = mask_index.is_some_and(|index| pixel[index] == 0.0);
+        if is_nodata || is_transparent || is_masked {
+            *valid = false;
+            pixel.fill(f64::NAN);
+        }
+    }
+    if num_warped > num_bands {
+        let data = image
+            .data
+            .chunks_exact(num_warped)
+            .flat_map(|pixel| pixel[..num_bands].iter().copied())
+            .collect();
+        image = ImageData::from_vec(window.width, window.height, num_bands, data);
+    }
+    Ok((image, valid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ds_utils::Resampling;
+    use crate::source::open_source;
+
+    #[test]
+    fn test_extract_window_validity() {
+        // A 2x2 raster with 0 as nodata
+        let path =
+            std::env::temp_dir().join(format!("tilemachine_window_{}.tif", std::process::id()));
+        let path = path.to_str().unwrap();
+        {
+            let mut ds = DriverManager::get_driver_by_name("GTiff")
+                .unwrap()
+                .create_with_band_type::<f64, _>(path, 2, 2, 1)
+                .unwrap();
+            ds.set_geo_transform(&[0.0, 1.0, 0.0, 2.0, 0.0, -1.0])
+                .unwrap();
+            ds.set_spatial_ref(&SpatialRef::from_epsg(3857).unwrap())
+                .unwrap();
+            let mut band = ds.rasterband(1).unwrap();
+            band.set_no_data_value(Some(0.0)).unwrap();
+            band.write(
+                (0, 0),
+                (2, 2),
+                &Buffer::new((2, 2), vec![0.0, 1.0, 2.0, 3.0]),
+            )
+            .unwrap();
+        }
+        let source = open_source(&format!("file:{}", path)).unwrap();
+        // The window extends one pixel to the right of the raster
+        let window = Window {
+            bbox: BoundingBox {
+                xmin: 0.0,
+                ymin: 0.0,
+                xmax: 3.0,
+                ymax: 2.0,
+            },
+            epsg: 3857,
+            width: 3,
+            height: 2,
+        };
+        let options = ReprojectOptions::default();
+        let (image, valid) = extract_window(source.as_ref(), &window, &options).unwrap();
+        assert_eq!(valid, vec![false, true, false, true, true, false]);
+        assert!(image.pixel_data(0, 0)[0].is_nan());
+        assert_eq!(image.pixel_data(0, 1), &[1.0]);
+        assert_eq!(image.pixel_data(1, 1), &[3.0]);
+        assert!(image.pixel_data(1, 2)[0].is_nan());
+
+        // A window far away from the raster
+        let window = Window {
+            bbox: BoundingBox {
+                xmin: 1000000.0,
+                ymin: 1000000.0,
+                xmax: 1000003.0,
+                ymax: 1000002.0,
+            },
+            epsg: 3857,
+            width: 3,
+            height: 2,
+        };
+        let (image, valid) = extract_window(source.as_ref(), &window, &options).unwrap();
+        assert_eq!(valid, vec![false; 6]);
+        assert!(image.data.iter().all(|v| v.is_nan()));
+
+        // A window whose pixels are much larger than the raster
+        let window = Window {
+            bbox: BoundingBox {
+                xmin: 0.0,
+                ymin: 0.0,
+                xmax: 3000.0,
+                ymax: 2000.0,
+            },
+            epsg: 3857,
+            width: 3,
+            height: 2,
+        };
+        let (_, valid) = extract_window(source.as_ref(), &window, &options).unwrap();
+        assert_eq!(valid, vec![false; 6]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_window_mask() {
+        // A 2x1 raster whose second pixel is masked out
+        let path = std::env::temp_dir().join(format!(
+            "tilemachine_window_mask_{}.tif",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        {
+            let mut ds = DriverManager::get_driver_by_name("GTiff")
+                .unwrap()
+                .create_with_band_type::<f64, _>(path, 2, 1, 1)
+                .unwrap();
+            ds.set_geo_transform(&[0.0, 1.0, 0.0, 1.0, 0.0, -1.0])
+                .unwrap();
+            ds.set_spatial_ref(&SpatialRef::from_epsg(3857).unwrap())
+                .unwrap();
+            let mut band = ds.rasterband(1).unwrap();
+            band.write((0, 0), (2, 1), &Buffer::new((2, 1), vec![1.0, 0.0]))
+                .unwrap();
+            band.create_mask_band(true).unwrap();
+            band.open_mask_band()
+                .unwrap()
+                .write((0, 0), (2, 1), &Buffer::new((2, 1), vec![255u8, 0]))
+                .unwrap();
+        }
+        let source = open_source(&format!("file:{}", path)).unwrap();
+        assert!(source.has_mask());
+        let window = Window {
+            bbox: BoundingBox {
+                xmin: 0.0,
+                ymin: 0.0,
+                xmax: 2.0,
+                ymax: 1.0,
+            },
+            epsg: 3857,
+            width: 2,
+            height: 1,
+        };
+        let options = ReprojectOptions {
+            resampling: Resampling::Nearest,
+            ..Default::default()
+        };
+        let (image, valid) = extract_window(source.as_ref(), &window, &options).unwrap();
+        assert_eq!(valid, vec![true, false]);
+        assert_eq!(image.channels, 1);
+        assert_eq!(image.pixel_data(0, 0), &[1.0]);
+        assert!(image.pixel_data(0, 1)[0].is_nan());
+        drop(source);
+        std::fs::remove_file(path).unwrap();
+        // The mask is internal or in a sidecar file depending on the GDAL version
+        let _ = std::fs::remove_file(format!("{}.msk", path));
+    }
+
+    #[test]
+    fn test_extract_window_bands() {
+        let path = std::env::temp_dir().join(format!(
+            "tilemachine_window_bands_{}.tif",
+            std::process::id()
+        ));
+        let path = path.to_str().unwrap();
+        {
+            let mut ds = DriverManager::get_driver_by_name("GTiff")
+                .unwrap()
+                .create_with_band_type::<f64, _>(path, 1, 1, 3)
+                .unwrap();
+            ds.set_geo_transform(&[0.0, 1.0, 0.0, 1.0, 0.0, -1.0])
+                .unwrap();
+            ds.set_spatial_ref(&SpatialRef::from_epsg(3857).unwrap())
+                .unwrap();
+            for i in 1..=3 {
+                ds.rasterband(i)
+                    .unwrap()
+                    .write((0, 0), (1, 1), &Buffer::new((1, 1), vec![i as f64]))
+                    .unwrap();
+            }
+        }
+        let source = open_source(&format!("file:{}", path)).unwrap();
+        let window = Window {
+            bbox: BoundingBox {
+                xmin: 0.0,
+                ymin: 0.0,
+                xmax: 1.0,
+                ymax: 1.0,
+            },
+            epsg: 3857,
+            width: 1,
+            height: 1,
+        };
+        let options = ReprojectOptions {
+            bands: Some(vec![3, 1]),
+            ..Default::default()
+        };
+        let (image, valid) = extract_window(source.as_ref(), &window, &options).unwrap();
+        assert_eq!(valid, vec![true]);
+        assert_eq!(image.channels, 2);
+        assert_eq!(image.pixel_data(0, 0), &[3.0, 1.0]);
+
+        let options = ReprojectOptions {
+            bands: Some(vec![4]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            extract_window(source.as_ref(), &window, &options),
+            Err(Error::InvalidParameter(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
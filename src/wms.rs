@@ -1,25 +1,278 @@
 use crate::bbox::BoundingBox;
 use crate::custom_script::CustomScript;
+use crate::encoding::OutputFormat;
 use crate::source::Source;
-use crate::utils::Result;
+use crate::utils::{Error, Result};
+use crate::window::Window;
+use gdal::spatial_ref::SpatialRef;
+use gdal_sys::{OSREPSGTreatsAsLatLong, OSREPSGTreatsAsNorthingEasting};
 use handlebars::Handlebars;
 use serde_json::json;
+use std::collections::HashMap;
 
+// TODO: Give specific/unique names
+pub const LAYER_NAME: &str = "image";
+
+// Those should match what is advertised in wms_capabilities.xml
+const MAX_SIZE: usize = 2048;
+const SUPPORTED_FORMATS: [&str; 4] = [
+    "image/png",
+    "image/jpeg",
+    "image/webp",
+    "image/tiff; application=geotiff",
+];
+
+pub enum WmsRequest {
+    GetCapabilities,
+    GetMap(GetMapRequest),
+}
+
+pub struct GetMapRequest {
+    pub layers: Vec<String>,
+    pub format: OutputFormat,
+    pub window: Window,
+}
+
+/// Parse the query parameters of a WMS request. Parameter names are case-insensitive as
+/// required by the WMS spec. A request without a REQUEST parameter is treated as GetCapabilities
+pub fn parse_request(query: &HashMap<String, String>) -> Result<WmsRequest> {
+    let params: HashMap<String, &str> = query
+        .iter()
+        .map(|(k, v)| (k.to_uppercase(), v.as_str()))
+        .collect();
+    match params.get("REQUEST") {
+        None => Ok(WmsRequest::GetCapabilities),
+        Some(r) if r.eq_ignore_ascii_case("GetCapabilities") => Ok(WmsRequest::GetCapabilities),
+        Some(r) if r.eq_ignore_ascii_case("GetMap") => {
+            Ok(WmsRequest::GetMap(parse_get_map(&params)?))
+        }
+        Some(r) => Err(Error::InvalidParameter(format!(
+            "Unsupported REQUEST: {}",
+            r
+        ))),
+    }
+}
+
+fn get_param<'a>(params: &HashMap<String, &'a str>, name: &str) -> Result<&'a str> {
+    params
+        .get(name)
+        .copied()
+        .ok_or_else(|| Error::InvalidParameter(format!("Missing {} parameter", name)))
+}
+
+fn parse_size(params: &HashMap<String, &str>, name: &str) -> Result<usize> {
+    let value = get_param(params, name)?;
+    match value.parse::<usize>() {
+        Ok(size) if size > 0 && size <= MAX_SIZE => Ok(size),
+        _ => Err(Error::InvalidParameter(format!(
+            "{} must be between 1 and {}, got {}",
+            name, MAX_SIZE, value
+        ))),
+    }
+}
+
+/// Whether the authority axis order of the given EPSG CRS is lat/lon (e.g. EPSG:4326 and most
+/// geographic CRS) or northing/easting, which WMS 1.3.0 bounding boxes follow
+pub fn epsg_swaps_axes(epsg: u32) -> Result<bool> {
+    let srs = SpatialRef::from_epsg(epsg)?;
+    let hsrs = srs.to_c_hsrs();
+    Ok(unsafe { OSREPSGTreatsAsLatLong(hsrs) != 0 || OSREPSGTreatsAsNorthingEasting(hsrs) != 0 })
+}
+
+// Returns the EPSG code for the given CRS and whether the BBOX axes are swapped. WMS 1.3.0
+// follows the CRS axis order, e.g. lat/lon for EPSG:4326, while CRS:84 is lon/lat
+fn parse_crs(crs: &str, version: &str) -> Result<(u32, bool)> {
+    if crs.eq_ignore_ascii_case("CRS:84") {
+        return Ok((4326, false));
+    }
+    let code = crs
+        .split_once(':')
+        .filter(|(authority, _)| authority.eq_ignore_ascii_case("EPSG"))
+        .and_then(|(_, code)| code.parse::<u32>().ok())
+        .ok_or_else(|| Error::InvalidParameter(format!("Unsupported CRS: {}", crs)))?;
+    if version != "1.3.0" {
+        return Ok((code, false));
+    }
+    let swap_axes = epsg_swaps_axes(code)
+        .map_err(|_| Error::InvalidParameter(format!("Unsupported CRS: {}", crs)))?;
+    Ok((code, swap_axes))
+}
+
+fn parse_bbox(bbox: &str, swap_axes: bool) -> Result<BoundingBox> {
+    let values: Vec<f64> = bbox
+        .split(',')
+        .map(|v| v.trim().parse::<f64>())
+        .collect::<std::result::Result<_, _>>()
+        .map_err(|_| Error::InvalidParameter(format!("Invalid BBOX: {}", bbox)))?;
+    if values.len() != 4 {
+        return Err(Error::InvalidParameter(format!("Invalid BBOX: {}", bbox)));
+    }
+    let bbox = if swap_axes {
+        BoundingBox {
+            xmin: values[1],
+            ymin: values[0],
+            xmax: values[3],
+            ymax: values[2],
+        }
+    } else {
+        BoundingBox {
+            xmin: values[0],
+            ymin: values[1],
+            xmax: values[2],
+            ymax: values[3],
+        }
+    };
+    if bbox.xmin >= bbox.xmax || bbox.ymin >= bbox.ymax {
+        return Err(Error::InvalidParameter(format!("Empty BBOX: {:?}", values)));
+    }
+    Ok(bbox)
+}
+
+fn parse_get_map(params: &HashMap<String, &str>) -> Result<GetMapRequest> {
+    let version = params.get("VERSION").copied().unwrap_or("1.3.0");
+    // WMS 1.1.1 uses SRS instead of CRS
+    let crs = get_param(params, "CRS").or_else(|_| get_param(params, "SRS"))?;
+    let (epsg, swap_axes) = parse_crs(crs, version)?;
+
+    let layers: Vec<String> = get_param(params, "LAYERS")?
+        .split(',')
+        .map(|l| l.to_string())
+        .collect();
+    if let Some(layer) = layers.iter().find(|l| l.as_str() != LAYER_NAME) {
+        return Err(Error::InvalidParameter(format!("Unknown layer: {}", layer)));
+    }
+
+    let format = get_param(params, "FORMAT")?;
+    let format = OutputFormat::from_mime_type(format)
+        .ok_or_else(|| Error::InvalidParameter(format!("Unsupported FORMAT: {}", format)))?;
+
+    Ok(GetMapRequest {
+        layers,
+        format,
+        window: Window {
+            bbox: parse_bbox(get_param(params, "BBOX")?, swap_axes)?,
+            epsg,
+            width: parse_size(params, "WIDTH")?,
+            height: parse_size(params, "HEIGHT")?,
+        },
+    })
+}
+
+/// `service_url` is the URL clients should use for subsequent requests (e.g. GetMap)
+/// `params_query` is added to the service URL, see custom_script::params_query_string
 pub fn capabilities(
     script: &CustomScript,
+    service_url: &str,
+    params_query: &str,
     open_source_fn: &dyn Fn(&str) -> Result<Box<dyn Source>>,
 ) -> Result<String> {
     let bbox = script.get_bounds(open_source_fn)?;
-    // TODO: Give specific/unique names
-    get_capabilities_xml("image", bbox)
+    get_capabilities_xml(LAYER_NAME, service_url, params_query, bbox)
 }
 
-fn get_capabilities_xml(layer_name: &str, layer_bbox: BoundingBox) -> Result<String> {
+fn get_capabilities_xml(
+    layer_name: &str,
+    service_url: &str,
+    params_query: &str,
+    layer_bbox: BoundingBox,
+) -> Result<String> {
     let reg = Handlebars::new();
     let tpl = include_str!("wms_capabilities.xml");
     reg.render_template(
         tpl,
-        &json!({"service_name": "tilemachine", "layer_name": layer_name, "bbox": layer_bbox.to_array()}),
+        &json!({
+            "service_name": "tilemachine",
+            "service_url": service_url,
+            "params_query": params_query,
+            "layer_name": layer_name,
+            "bbox": layer_bbox.to_array(),
+            "formats": SUPPORTED_FORMATS
+        }),
     )
     .map_err(|e| e.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(params: &[(&str, &str)]) -> HashMap<String, String> {
+        params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_get_map() {
+        let q = query(&[
+            ("service", "WMS"),
+            ("request", "GetMap"),
+            ("version", "1.3.0"),
+            ("layers", "image"),
+            ("crs", "EPSG:4326"),
+            ("bbox", "-41.0,174.0,-40.0,175.0"),
+            ("width", "512"),
+            ("height", "256"),
+            ("format", "image/png"),
+        ]);
+        let request = match parse_request(&q).unwrap() {
+            WmsRequest::GetMap(r) => r,
+            _ => panic!("expected GetMap"),
+        };
+        assert_eq!(request.window.epsg, 4326);
+        assert_eq!(request.window.width, 512);
+        assert_eq!(request.window.height, 256);
+        // EPSG:4326 in WMS 1.3.0 is lat/lon
+        assert_eq!(request.window.bbox.to_array(), [174.0, -41.0, 175.0, -40.0]);
+        assert_eq!(request.format, OutputFormat::Png);
+    }
+
+    #[test]
+    fn test_parse_crs() {
+        // Other geographic CRS are lat/lon too
+        assert_eq!(parse_crs("EPSG:4258", "1.3.0").unwrap(), (4258, true));
+        assert_eq!(parse_crs("EPSG:4269", "1.3.0").unwrap(), (4269, true));
+        assert_eq!(parse_crs("EPSG:4258", "1.1.1").unwrap(), (4258, false));
+        assert_eq!(parse_crs("EPSG:3857", "1.3.0").unwrap(), (3857, false));
+        assert_eq!(parse_crs("CRS:84", "1.3.0").unwrap(), (4326, false));
+        assert!(parse_crs("EPSG:999999", "1.3.0").is_err());
+
+        let q = query(&[
+            ("request", "GetMap"),
+            ("layers", "image"),
+            ("crs", "EPSG:4258"),
+            ("bbox", "46.0,6.0,47.0,7.0"),
+            ("width", "256"),
+            ("height", "256"),
+            ("format", "image/png"),
+        ]);
+        let request = match parse_request(&q).unwrap() {
+            WmsRequest::GetMap(r) => r,
+            _ => panic!("expected GetMap"),
+        };
+        assert_eq!(request.window.bbox.to_array(), [6.0, 46.0, 7.0, 47.0]);
+    }
+
+    #[test]
+    fn test_parse_get_map_invalid() {
+        let mut q = query(&[
+            ("REQUEST", "GetMap"),
+            ("LAYERS", "image"),
+            ("CRS", "EPSG:3857"),
+            ("BBOX", "0,0,10,10"),
+            ("WIDTH", "4096"),
+            ("HEIGHT", "256"),
+            ("FORMAT", "image/png"),
+        ]);
+        assert!(parse_request(&q).is_err());
+        q.insert("WIDTH".to_string(), "256".to_string());
+        assert!(parse_request(&q).is_ok());
+        q.insert("FORMAT".to_string(), "image/gif".to_string());
+        assert!(parse_request(&q).is_err());
+        q.insert("FORMAT".to_string(), "image/jpeg".to_string());
+        assert!(parse_request(&q).is_ok());
+        q.insert("LAYERS".to_string(), "other".to_string());
+        assert!(parse_request(&q).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+//! WMTS 1.0.0 service, with both the KVP and the RESTful encodings. Every tile matrix set (see
+//! `tms`) is published and tiles are rendered like XYZ tiles
+use crate::bbox::BoundingBox;
+use crate::custom_script::CustomScript;
+use crate::encoding::OutputFormat;
+use crate::source::Source;
+use crate::tms::{TileMatrixSet, TileMatrixSets};
+use crate::utils::{Error, Result};
+use crate::wms::LAYER_NAME;
+use handlebars::Handlebars;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+pub const STYLE: &str = "default";
+
+// The formats tiles are published in, as extensions for the RESTful tile URLs
+const FORMAT_EXTENSIONS: [&str; 5] = ["png", "jpg", "webp", "tif", "bin"];
+
+pub enum WmtsRequest {
+    GetCapabilities,
+    GetTile(GetTileRequest),
+}
+
+pub struct GetTileRequest {
+    pub format: OutputFormat,
+    pub tile_matrix_set: String,
+    pub tile_matrix: String,
+    pub row: u64,
+    pub col: u64,
+}
+
+/// Parse the query parameters of a KVP request. As for WMS, parameter names are
+/// case-insensitive and a request without a REQUEST parameter is treated as GetCapabilities
+pub fn parse_request(query: &HashMap<String, String>) -> Result<WmtsRequest> {
+    let params: HashMap<String, &str> = query
+        .iter()
+        .map(|(k, v)| (k.to_uppercase(), v.as_str()))
+        .collect();
+    match params.get("REQUEST") {
+        None => Ok(WmtsRequest::GetCapabilities),
+        Some(r) if r.eq_ignore_ascii_case("GetCapabilities") => Ok(WmtsRequest::GetCapabilities),
+        Some(r) if r.eq_ignore_ascii_case("GetTile") => {
+            let get_param = |name: &str| {
+                params
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| Error::InvalidParameter(format!("Missing {} parameter", name)))
+            };
+            let format = get_param("FORMAT")?;
+            let format = OutputFormat::from_mime_type(format).ok_or_else(|| {
+                Error::InvalidParameter(format!("Unsupported FORMAT: {}", format))
+            })?;
+            parse_get_tile(
+                get_param("LAYER")?,
+                get_param("STYLE")?,
+                format,
+                get_param("TILEMATRIXSET")?,
+                get_param("TILEMATRIX")?,
+                get_param("TILEROW")?,
+                get_param("TILECOL")?,
+            )
+            .map(WmtsRequest::GetTile)
+        }
+        Some(r) => Err(Error::InvalidParameter(format!(
+            "Unsupported REQUEST: {}",
+            r
+        ))),
+    }
+}
+
+/// Parse the path segments of a RESTful GetTile request, which follow the ResourceURL template
+/// advertised in the capabilities. The last one is the column with the format extension
+pub fn parse_rest_get_tile(
+    layer: &str,
+    style: &str,
+    tile_matrix_set: &str,
+    tile_matrix: &str,
+    row: &str,
+    col: &str,
+) -> Result<GetTileRequest> {
+    let (col, extension) = col
+        .split_once('.')
+        .ok_or_else(|| Error::InvalidPath(format!("Missing format extension: {}", col)))?;
+    let format = OutputFormat::from_extension(extension).ok_or_else(|| {
+        Error::InvalidPath(format!("Unsupported format extension: {}", extension))
+    })?;
+    parse_get_tile(layer, style, format, tile_matrix_set, tile_matrix, row, col)
+}
+
+fn parse_get_tile(
+    layer: &str,
+    style: &str,
+    format: OutputFormat,
+    tile_matrix_set: &str,
+    tile_matrix: &str,
+    row: &str,
+    col: &str,
+) -> Result<GetTileRequest> {
+    if layer != LAYER_NAME {
+        return Err(Error::InvalidParameter(format!("Unknown layer: {}", layer)));
+    }
+    if !style.is_empty() && style != STYLE {
+        return Err(Error::InvalidParameter(format!("Unknown style: {}", style)));
+    }
+    let parse_index = |name: &str, value: &str| {
+        value
+            .parse::<u64>()
+            .map_err(|_| Error::InvalidParameter(format!("Invalid {}: {}", name, value)))
+    };
+    Ok(GetTileRequest {
+        format,
+        tile_matrix_set: tile_matrix_set.to_string(),
+        tile_matrix: tile_matrix.to_string(),
+        row: parse_index("TILEROW", row)?,
+        col: parse_index("TILECOL", col)?,
+    })
+}
+
+/// `service_url` is the base URL of the service, which the KVP (`/service`) and RESTful
+/// (`/1.0.0/...`, `/tile/1.0.0/...`) URLs are relative to
+/// `params_query` is added to the tile URLs, see custom_script::params_query_string
+pub fn capabilities(
+    script: &CustomScript,
+    tile_matrix_sets: &TileMatrixSets,
+    service_url: &str,
+    params_query: &str,
+    open_source_fn: &dyn Fn(&str) -> Result<Box<dyn Source>>,
+) -> Result<String> {
+    let bbox = script.get_bounds(open_source_fn)?;
+    let formats: Vec<OutputFormat> = FORMAT_EXTENSIONS
+        .iter()
+        .filter_map(|extension| OutputFormat::from_extension(extension))
+        .filter(|format| format.supports(script.has_float_output()))
+        .collect();
+    get_capabilities_xml(
+        service_url,
+        params_query,
+        bbox,
+        &formats,
+        &tile_matrix_sets.all(),
+    )
+}
+
+fn tile_matrix_set_json(tms: &TileMatrixSet) -> Result<Value> {
+    let tile_matrices: Vec<Value> = tms
+        .tile_matrices
+        .iter()
+        .map(|matrix| {
+            json!({
+                "identifier": matrix.id,
+                "scale_denominator": matrix.scale_denominator,
+                "top_left_corner": tms.top_left_corner(matrix),
+                "tile_width": matrix.tile_width,
+                "tile_height": matrix.tile_height,
+                "matrix_width": matrix.matrix_width,
+                "matrix_height": matrix.matrix_height,
+            })
+        })
+        .collect();
+    Ok(json!({
+        "identifier": tms.id,
+        "crs": tms.crs_urn()?,
+        "tile_matrices": tile_matrices,
+    }))
+}
+
+fn get_capabilities_xml(
+    service_url: &str,
+    params_query: &str,
+    layer_bbox: BoundingBox,
+    formats: &[OutputFormat],
+    tile_matrix_sets: &[&TileMatrixSet],
+) -> Result<String> {
+    let reg = Handlebars::new();
+    let tpl = include_str!("wmts_capabilities.xml");
+    let formats: Vec<Value> = formats
+        .iter()
+        .map(|format| json!({"mime_type": format.mime_type(), "extension": format.extension()}))
+        .collect();
+    let tile_matrix_sets = tile_matrix_sets
+        .iter()
+        .map(|tms| tile_matrix_set_json(tms))
+        .collect::<Result<Vec<Value>>>()?;
+    reg.render_template(
+        tpl,
+        &json!({
+            "service_name": "tilemachine",
+            "service_url": service_url,
+            "params_query": params_query,
+            "layer_name": LAYER_NAME,
+            "style": STYLE,
+            "bbox": layer_bbox.to_array(),
+            "formats": formats,
+            "tile_matrix_sets": tile_matrix_sets,
+        }),
+    )
+    .map_err(|e| e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(params: &[(&str, &str)]) -> HashMap<String, String> {
+        params
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_get_tile() {
+        let mut q = query(&[
+            ("service", "WMTS"),
+            ("request", "GetTile"),
+            ("version", "1.0.0"),
+            ("layer", "image"),
+            ("style", "default"),
+            ("format", "image/jpeg"),
+            ("tilematrixset", "WebMercatorQuad"),
+            ("tilematrix", "4"),
+            ("tilerow", "5"),
+            ("tilecol", "3"),
+        ]);
+        let request = match parse_request(&q).unwrap() {
+            WmtsRequest::GetTile(r) => r,
+            _ => panic!("expected GetTile"),
+        };
+        assert_eq!(request.tile_matrix_set, "WebMercatorQuad");
+        assert_eq!(request.tile_matrix, "4");
+        assert_eq!((request.row, request.col), (5, 3));
+        assert!(matches!(request.format, OutputFormat::Jpeg { .. }));
+
+        q.insert("tilerow".to_string(), "-1".to_string());
+        assert!(parse_request(&q).is_err());
+        q.remove("tilerow");
+        assert!(parse_request(&q).is_err());
+
+        let request =
+            parse_rest_get_tile("image", "default", "WorldCRS84Quad", "2", "1", "7.png").unwrap();
+        assert_eq!(request.format, OutputFormat::Png);
+        assert_eq!((request.row, request.col), (1, 7));
+        assert!(parse_rest_get_tile("image", "default", "WorldCRS84Quad", "2", "1", "7").is_err());
+        assert!(
+            parse_rest_get_tile("other", "default", "WorldCRS84Quad", "2", "1", "7.png").is_err()
+        );
+        assert!(
+            parse_rest_get_tile("image", "fancy", "WorldCRS84Quad", "2", "1", "7.png").is_err()
+        );
+    }
+
+    #[test]
+    fn test_capabilities_xml() {
+        let bbox = BoundingBox {
+            xmin: 174.0,
+            ymin: -41.0,
+            xmax: 175.0,
+            ymax: -40.0,
+        };
+        let sets = TileMatrixSets::new(vec![]);
+        let xml = get_capabilities_xml(
+            "http://localhost/wmts/script",
+            "",
+            bbox.clone(),
+            &[OutputFormat::Png],
+            &sets.all(),
+        )
+        .unwrap();
+        assert!(xml.contains("<ows:LowerCorner>174.0 -41.0</ows:LowerCorner>"));
+        assert!(xml.contains("<ows:SupportedCRS>urn:ogc:def:crs:EPSG::3857</ows:SupportedCRS>"));
+        assert!(xml.contains(
+            "template=\"http://localhost/wmts/script/tile/1.0.0/image/{Style}/{TileMatrixSet}/{TileMatrix}/{TileRow}/{TileCol}.png\""
+        ));
+        assert!(xml.contains("<TopLeftCorner>-180.0 90.0</TopLeftCorner>"));
+        assert!(xml.contains("<MatrixWidth>16777216</MatrixWidth>"));
+        assert!(roxmltree::Document::parse(&xml).is_ok());
+
+        // Script parameters are kept in the tile URLs
+        let xml = get_capabilities_xml(
+            "http://localhost/wmts/script",
+            "param.a=1&param.b=2",
+            bbox,
+            &[OutputFormat::Png],
+            &sets.all(),
+        )
+        .unwrap();
+        let doc = roxmltree::Document::parse(&xml).unwrap();
+        let template = doc
+            .descendants()
+            .find(|n| n.has_tag_name("ResourceURL"))
+            .and_then(|n| n.attribute("template"))
+            .unwrap();
+        assert!(template.ends_with("{TileCol}.png?param.a=1&param.b=2"));
+        assert!(doc.descendants().any(|n| {
+            n.has_tag_name("Get")
+                && n.attributes().any(|a| {
+                    a.value() == "http://localhost/wmts/script/service?param.a=1&param.b=2&"
+                })
+        }));
+    }
+}
@@ -1,9 +1,11 @@
 use std::f64::consts::PI;
+use std::ops::RangeInclusive;
 
+use crate::bbox::BoundingBox;
+use crate::ds_utils::ReprojectOptions;
 use crate::source::Source;
-use crate::{ds_utils::read_ds_at_once, utils::ImageData};
-use gdal::{spatial_ref::SpatialRef, DriverManager};
-use gdal_sys::OSRAxisMappingStrategy;
+use crate::utils::{Error, ImageData, Result};
+use crate::window::{extract_window, Window};
 
 // This is the WGS_1984 spheroid radius in meters
 // https://epsg.io/3857
@@ -23,6 +25,10 @@ const EPSG_3857_ORIGIN_SHIFT: f64 = EQUATOR_LENGTH_M / 2.0;
 
 pub const TILE_SIZE: u64 = 256;
 
+/// The sizes (in pixels) tiles can be rendered at. Larger tiles cover the same area as a 256
+/// tile, with a finer resolution (e.g. 512 for high-DPI screens)
+pub const TILE_SIZES: [usize; 3] = [256, 512, 1024];
+
 pub struct TileCoords {
     pub x: u64,
     pub y: u64,
@@ -33,10 +39,33 @@ pub struct TileCoords {
 // has quite large resolution deformation as you move away from the equator
 // https://wiki.openstreetmap.org/wiki/Slippy_map_tilenames#Resolution_and_Scale
 // https://gist.githubusercontent.com/maptiler/fddb5ce33ba995d5523de9afdf8ef118/raw/d7565390d2480bfed3c439df5826f1d9e4b41761/globalmaptiles.py
-fn resolution_at_zoom(zoom: u64) -> f64 {
+pub fn resolution_at_zoom(zoom: u64) -> f64 {
     INITIAL_RESOLUTION / (2.0_f64.powf(zoom as f64))
 }
 
+/// Returns the lowest zoom level whose resolution is at least as fine as `resolution` (in
+/// EPSG:3857 meters), capped at `max_zoom`
+pub fn zoom_for_resolution(resolution: f64, max_zoom: u64) -> u64 {
+    (0..max_zoom)
+        .find(|zoom| resolution_at_zoom(*zoom) <= resolution)
+        .unwrap_or(max_zoom)
+}
+
+/// Returns the (inclusive) ranges of XYZ tile x and y covering the given EPSG:3857 bbox
+pub fn tiles_covering(bbox: &BoundingBox, zoom: u64) -> (RangeInclusive<u64>, RangeInclusive<u64>) {
+    let tile_span = resolution_at_zoom(zoom) * TILE_SIZE as f64;
+    let max_index = 2.0_f64.powf(zoom as f64) - 1.0;
+    // The max bound is exclusive, so a bbox ending exactly on a tile edge doesn't include the
+    // next tile
+    let first = |v: f64| (v / tile_span).floor().clamp(0.0, max_index) as u64;
+    let last = |v: f64| ((v / tile_span).ceil() - 1.0).clamp(0.0, max_index) as u64;
+    // XYZ tiles are numbered from the top left corner
+    (
+        first(bbox.xmin + EPSG_3857_ORIGIN_SHIFT)..=last(bbox.xmax + EPSG_3857_ORIGIN_SHIFT),
+        first(EPSG_3857_ORIGIN_SHIFT - bbox.ymax)..=last(EPSG_3857_ORIGIN_SHIFT - bbox.ymin),
+    )
+}
+
 // Converts from xy coordinates in given zoom level of the pyramid to EPSG:3857
 fn pixels_to_3857_meters(zx: u64, zy: u64, zoom: u64) -> (f64, f64) {
     let res = resolution_at_zoom(zoom);
@@ -46,19 +75,10 @@ fn pixels_to_3857_meters(zx: u64, zy: u64, zoom: u64) -> (f64, f64) {
     )
 }
 
-struct TileBounds {
-    pub xmin: f64,
-    #[allow(dead_code)]
-    pub ymin: f64,
-    #[allow(dead_code)]
-    pub xmax: f64,
-    pub ymax: f64,
-}
-
-fn compute_tile_bounds(x: u64, y: u64, zoom: u64) -> TileBounds {
+fn compute_tile_bounds(x: u64, y: u64, zoom: u64) -> BoundingBox {
     let (xmin, ymin) = pixels_to_3857_meters(x * TILE_SIZE, y * TILE_SIZE, zoom);
     let (xmax, ymax) = pixels_to_3857_meters((x + 1) * TILE_SIZE, (y + 1) * TILE_SIZE, zoom);
-    TileBounds {
+    BoundingBox {
         xmin,
         ymin,
         xmax,
@@ -66,45 +86,117 @@ fn compute_tile_bounds(x: u64, y: u64, zoom: u64) -> TileBounds {
     }
 }
 
-pub fn extract_tile(source: &dyn Source, coords: &TileCoords) -> ImageData<f64> {
+/// Parses the last segment of a tile URL, which is either `{x}` or `{x}@{scale}x` for high-DPI
+/// tiles (e.g. `12@2x`), and returns x with the tile size in pixels
+pub fn parse_tile_x(segment: &str) -> Result<(u64, usize)> {
+    let invalid = || Error::InvalidPath(format!("invalid tile x: {}", segment));
+    let (x, scale) = match segment.split_once('@') {
+        Some((x, scale)) => {
+            let scale = scale.strip_suffix('x').ok_or_else(invalid)?;
+            (x, scale.parse::<usize>().map_err(|_| invalid())?)
+        }
+        None => (segment, 1),
+    };
+    let x = x.parse::<u64>().map_err(|_| invalid())?;
+    let tile_size = scale * TILE_SIZE as usize;
+    if !TILE_SIZES.contains(&tile_size) {
+        return Err(Error::InvalidPath(format!(
+            "unsupported tile scale {}x, the tile size must be one of {:?}",
+            scale, TILE_SIZES
+        )));
+    }
+    Ok((x, tile_size))
+}
+
+/// Returns the EPSG:3857 window covered by the given XYZ tile, rendered at `tile_size` pixels
+pub fn tile_window(coords: &TileCoords, tile_size: usize) -> Window {
     // We serve XYZ tiles => reverse y
     // TODO: Is this the right place to do it ? Should this be in compute_tile_bounds ?
     let y = ((2.0_f64.powf(coords.zoom as f64) - 1.0) - coords.y as f64) as u64;
+    Window {
+        bbox: compute_tile_bounds(coords.x, y, coords.zoom),
+        epsg: 3857,
+        width: tile_size,
+        height: tile_size,
+    }
+}
 
-    // TODO: Early return if tile out of raster
-    // TODO: Early return if raster invisible in tile (covers too little)
-    let tile_srs = SpatialRef::from_epsg(3857).unwrap();
-    tile_srs.set_axis_mapping_strategy(OSRAxisMappingStrategy::OAMS_TRADITIONAL_GIS_ORDER);
-    let num_bands = source.num_bands();
-    let drv = DriverManager::get_driver_by_name("MEM").unwrap();
-    let mut tile_ds = drv
-        .create_with_band_type::<f64, _>(
-            "",
-            TILE_SIZE as isize,
-            TILE_SIZE as isize,
-            num_bands as isize,
-        )
-        .unwrap();
-
-    let tile_bounds = compute_tile_bounds(coords.x, y, coords.zoom);
-
-    let pixel_size = resolution_at_zoom(coords.zoom);
-    let tile_geo = [
-        tile_bounds.xmin,
-        pixel_size,
-        0.0,
-        tile_bounds.ymax,
-        0.0,
-        -pixel_size,
-    ];
-
-    tile_ds.set_geo_transform(&tile_geo).unwrap();
-    tile_ds.set_spatial_ref(&tile_srs).unwrap();
-
-    source.reproject_to(&tile_ds).unwrap();
-    println!(
+/// See `extract_window`
+pub fn extract_tile(
+    source: &dyn Source,
+    coords: &TileCoords,
+    tile_size: usize,
+    options: &ReprojectOptions,
+) -> Result<(ImageData<f64>, Vec<bool>)> {
+    let window = tile_window(coords, tile_size);
+    log::debug!(
         "extracting_tile for x={:?}, y={:?}, zoom={:?}, tile_geo={:?}",
-        coords.x, y, coords.zoom, tile_geo
+        coords.x,
+        coords.y,
+        coords.zoom,
+        window.geo_transform()
     );
-    read_ds_at_once(&tile_ds)
+    extract_window(source, &window, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zoom_for_resolution() {
+        assert_eq!(zoom_for_resolution(INITIAL_RESOLUTION, 20), 0);
+        assert_eq!(zoom_for_resolution(resolution_at_zoom(12) * 1.5, 20), 12);
+        assert_eq!(zoom_for_resolution(0.001, 20), 20);
+    }
+
+    #[test]
+    fn test_tiles_covering() {
+        let window = tile_window(
+            &TileCoords {
+                x: 3,
+                y: 5,
+                zoom: 4,
+            },
+            256,
+        );
+        let (xs, ys) = tiles_covering(&window.bbox, 4);
+        assert_eq!(xs, 3..=3);
+        assert_eq!(ys, 5..=5);
+        // Growing the bbox a bit should include the neighbouring tiles
+        let bbox = BoundingBox {
+            xmin: window.bbox.xmin - 1.0,
+            ymin: window.bbox.ymin - 1.0,
+            xmax: window.bbox.xmax + 1.0,
+            ymax: window.bbox.ymax + 1.0,
+        };
+        let (xs, ys) = tiles_covering(&bbox, 4);
+        assert_eq!(xs, 2..=4);
+        assert_eq!(ys, 4..=6);
+        // At zoom 0, everything is in the single tile
+        let (xs, ys) = tiles_covering(&bbox, 0);
+        assert_eq!(xs, 0..=0);
+        assert_eq!(ys, 0..=0);
+    }
+
+    #[test]
+    fn test_parse_tile_x() {
+        assert_eq!(parse_tile_x("12").unwrap(), (12, 256));
+        assert_eq!(parse_tile_x("12@2x").unwrap(), (12, 512));
+        assert_eq!(parse_tile_x("12@4x").unwrap(), (12, 1024));
+        assert!(parse_tile_x("12@3x").is_err());
+        assert!(parse_tile_x("12@2").is_err());
+        assert!(parse_tile_x("a").is_err());
+        // High-DPI tiles cover the same area
+        let coords = TileCoords {
+            x: 3,
+            y: 5,
+            zoom: 4,
+        };
+        let window = tile_window(&coords, 512);
+        assert_eq!(window.width, 512);
+        let window_256 = tile_window(&coords, 256);
+        assert_eq!(window.bbox.xmin, window_256.bbox.xmin);
+        assert_eq!(window.bbox.ymax, window_256.bbox.ymax);
+    }
 }
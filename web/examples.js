@@ -9,6 +9,22 @@ const EXAMPLES = {
     "inputs": {"rgb":"s3:rasters/new_zealand_1_rgb.tif","dsm":"s3:rasters/new_zealand_1_dsm.tif"},
     "script": "return [10 * dsm[0], 10 * dsm[0], 10 * dsm[0], 255]"
   },
+  "nz_dsm_tile": {
+    "title": "New zealand DSM (tile mode)",
+    "inputs": {"dsm":"s3:rasters/new_zealand_1_dsm.tif"},
+    "mode": "tile",
+    "script": `
+      // In tile mode, each input is an array of Float64Array (one per band) and the script
+      // returns the RGBA values of the whole tile
+      const heights = dsm[0]
+      const out = new Uint8ClampedArray(heights.length * 4)
+      for (let i = 0; i < heights.length; i++) {
+        const v = 10 * heights[i]
+        out.set([v, v, v, 255], 4 * i)
+      }
+      return out
+    `
+  },
   "palm_rgb": {
     "title": "Palm trees RGB",
     "inputs": {"optical":"s3:rasters/palm_rgb.tif"},
@@ -32,17 +48,23 @@ const EXAMPLES = {
   },
   "s2_ndvi": {
     "title": "Sentinel 2 NDVI",
-    "inputs": {"s2":"s3:rasters/s2_lausanne.tiff"},
+    "inputs": {
+      "s2": {
+        "path": "s3:rasters/s2_lausanne.tiff",
+        "bands": [{"band": 4, "name": "red"}, {"band": 8, "name": "nir"}]
+      }
+    },
     "script": `
-      let red = s2[3];
-      let nir = s2[7];
+      // Only the two selected bands are read
+      let red = s2.red;
+      let nir = s2.nir;
       let ndvi = (nir - red) / (nir + red);
       //const ndvi_u8 = 255.0 * ((ndvi + 1.0) / 2.0);
       //return [ndvi_u8, ndvi_u8, ndvi_u8, 255]
       // https://custom-scripts.sentinel-hub.com/custom-scripts/sentinel-2/ndvi/
       function cmap(v) {
           if (v < -0.2) {
-              return [0, 0, 0, 255, 255]
+              return [0, 0, 0, 255]
           } else if (v <= 0) {
               return [165, 0, 38, 255]
           } else if (v <= 0.1) {
@@ -112,7 +134,8 @@ function loadExample(scriptEditor, name) {
   const example = EXAMPLES[name];
   scriptEditor.setCustomScript({
     "inputs": example.inputs,
-    "script": example.script
+    "script": example.script,
+    "mode": example.mode
   })
 }
 
@@ -5,12 +5,26 @@ function makeEditor (divId) {
   return editor
 }
 
+// Register the script on the server and return its ID, which keeps tile URLs short
+async function registerScript (customScript) {
+  const resp = await fetch("/scripts", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify(customScript)
+  })
+  const body = await resp.json()
+  if (!resp.ok) {
+    throw new Error('Failed to register script: ' + body.message)
+  }
+  return body.id
+}
+
 // Load raster specified in current URL
-async function getBounds (customScript) {
-  const resp = await fetch("/bounds/" + encodeURIComponent(JSON.stringify(customScript)))
+async function getBounds (scriptId) {
+  const resp = await fetch("/bounds/" + scriptId)
   if (!resp.ok) {
-    const body = await resp.text()
-    throw new Error('Failed to get bounds :' + body)
+    const body = await resp.json()
+    throw new Error('Failed to get bounds: ' + body.message)
   }
   return resp.json()
 }
@@ -57,6 +71,11 @@ class CustomScriptEditor {
   setCustomScript (customScript) {
     this.data.inputs = customScript.inputs
     this.data.script = customScript.script
+    if (customScript.mode) {
+      this.data.mode = customScript.mode
+    } else {
+      delete this.data.mode
+    }
     this._updateEditorsFromData()
   }
 }
\ No newline at end of file